@@ -0,0 +1,109 @@
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::rate_limit::RateLimits;
+
+/// Server-wide configuration, read from environment variables on startup.
+pub struct Config {
+    /// How long a room may stay without any users or admins before it is removed (`ROOM_IDLE_TTL`, in seconds).
+    pub room_idle_ttl: Duration,
+    /// How often rooms are checked for being idle or expired (`ROOM_SWEEP_INTERVAL`, in seconds,
+    /// at least 1).
+    pub room_sweep_interval: Duration,
+    /// How long a user whose connection dropped keeps their place and match while they can
+    /// resume their session (`RECONNECT_GRACE_PERIOD`, in seconds).
+    pub reconnect_grace_period: Duration,
+    /// The largest WebSocket message accepted from a client, including messages reassembled from
+    /// continuation frames (`MAX_MESSAGE_SIZE`, in bytes, at least 1).
+    pub max_message_size: usize,
+    /// The most characters a `chat-message` may have (`MAX_CHAT_MESSAGE_LENGTH`).
+    pub max_chat_message_length: usize,
+    /// Limits on how many messages a connection may send (`RATE_LIMIT` as `<per second>/<burst>`,
+    /// `MESSAGE_RATE_LIMITS` as `<type>=<per second>/<burst>,...` and
+    /// `MAX_RATE_LIMIT_VIOLATIONS`).
+    pub rate_limits: RateLimits,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let defaults = Config::default();
+
+        Config {
+            room_idle_ttl: seconds_from_env("ROOM_IDLE_TTL", defaults.room_idle_ttl),
+            // sweeping needs a period to wait between checks
+            room_sweep_interval: from_env("ROOM_SWEEP_INTERVAL")
+                .filter(|seconds| positive("ROOM_SWEEP_INTERVAL", *seconds))
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.room_sweep_interval),
+            reconnect_grace_period: seconds_from_env(
+                "RECONNECT_GRACE_PERIOD",
+                defaults.reconnect_grace_period,
+            ),
+            // with no room for a payload every message would be closed as too big
+            max_message_size: from_env("MAX_MESSAGE_SIZE")
+                .filter(|size| positive("MAX_MESSAGE_SIZE", *size as u64))
+                .unwrap_or(defaults.max_message_size),
+            max_chat_message_length: from_env("MAX_CHAT_MESSAGE_LENGTH")
+                .unwrap_or(defaults.max_chat_message_length),
+            rate_limits: rate_limits_from_env(defaults.rate_limits),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            room_idle_ttl: Duration::from_secs(30 * 60),
+            room_sweep_interval: Duration::from_secs(60),
+            reconnect_grace_period: Duration::from_secs(30),
+            max_message_size: 64 * 1024,
+            max_chat_message_length: 1000,
+            rate_limits: RateLimits::default(),
+        }
+    }
+}
+
+fn rate_limits_from_env(defaults: RateLimits) -> RateLimits {
+    let mut rate_limits = defaults;
+    if let Some(connection) = from_env("RATE_LIMIT") {
+        rate_limits.connection = connection;
+    }
+    if let Some(max_violations) = from_env("MAX_RATE_LIMIT_VIOLATIONS") {
+        rate_limits.max_violations = max_violations;
+    }
+    // only the message types that are given replace their defaults
+    if let Ok(value) = env::var("MESSAGE_RATE_LIMITS") {
+        match RateLimits::parse_message_types(&value) {
+            Ok(message_types) => rate_limits.message_types.extend(message_types),
+            Err(error) => println!(
+                "⚠️ Ignoring invalid value {:?} for MESSAGE_RATE_LIMITS: {}",
+                value, error
+            ),
+        }
+    }
+
+    rate_limits
+}
+
+fn seconds_from_env(key: &str, default: Duration) -> Duration {
+    from_env(key).map(Duration::from_secs).unwrap_or(default)
+}
+
+fn positive(key: &str, value: u64) -> bool {
+    if value == 0 {
+        println!("⚠️ Ignoring invalid value {:?} for {}", value, key);
+    }
+    value > 0
+}
+
+fn from_env<T: FromStr>(key: &str) -> Option<T> {
+    let value = env::var(key).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            println!("⚠️ Ignoring invalid value {:?} for {}", value, key);
+            None
+        }
+    }
+}
@@ -2,11 +2,21 @@ use actix::{
     fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, ContextFutureSpawner, Handler,
     Running, StreamHandler, WrapFuture,
 };
+use actix_http::ws::Item;
+use actix_web::web::BytesMut;
 use actix_web_actors::ws;
+use std::io;
+use std::str::Utf8Error;
 use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+use crate::config::Config;
+use crate::handshake::ClientProtocol;
+use crate::matching::Profile;
 use crate::messages::{ClientConnect, ClientDisconnect, ClientMessage, WebSocketMessage};
+use crate::metrics::{self, ProtocolErrorKind};
+use crate::protocol::{ErrorCode, ServerEvent, Side};
+use crate::rate_limit::{RateLimiter, Verdict};
 use crate::room::Room;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -15,31 +25,86 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 pub struct Connection {
     room_addr: Addr<Room>,
     last_heartbeat: Instant,
+    /// The client's id in the room; taken over from the earlier session when a user resumes one.
     id: Uuid,
+    /// The id this connection was opened with, which identifies it even after a resumption.
+    connection_id: Uuid,
     kind: ConnectionKind,
+    /// What was negotiated with the client, or why the client is incompatible.
+    protocol: Result<ClientProtocol, String>,
+    /// Whether the client or the server closed the connection on purpose.
+    closed_deliberately: bool,
+    max_message_size: usize,
+    rate_limiter: RateLimiter,
+    /// The message whose continuation frames are still being received, if any.
+    fragmented_message: Option<FragmentedMessage>,
+}
+
+struct FragmentedMessage {
+    is_text: bool,
+    data: BytesMut,
 }
 
 enum ConnectionKind {
     Admin,
-    User(String),
+    User {
+        name: String,
+        resume_token: Option<String>,
+        side: Option<Side>,
+        profile: Profile,
+    },
 }
 
 impl Connection {
-    pub fn user(name: String, room_addr: Addr<Room>) -> Connection {
-        Connection {
-            id: Uuid::new_v4(),
-            kind: ConnectionKind::User(name),
+    pub fn user(
+        name: String,
+        resume_token: Option<String>,
+        side: Option<Side>,
+        profile: Profile,
+        room_addr: Addr<Room>,
+        protocol: Result<ClientProtocol, String>,
+        config: &Config,
+    ) -> Connection {
+        Connection::new(
+            ConnectionKind::User {
+                name,
+                resume_token,
+                side,
+                profile,
+            },
             room_addr,
-            last_heartbeat: Instant::now(),
-        }
+            protocol,
+            config,
+        )
+    }
+
+    pub fn admin(
+        room_addr: Addr<Room>,
+        protocol: Result<ClientProtocol, String>,
+        config: &Config,
+    ) -> Connection {
+        Connection::new(ConnectionKind::Admin, room_addr, protocol, config)
     }
 
-    pub fn admin(room_addr: Addr<Room>) -> Connection {
+    fn new(
+        kind: ConnectionKind,
+        room_addr: Addr<Room>,
+        protocol: Result<ClientProtocol, String>,
+        config: &Config,
+    ) -> Connection {
+        let id = Uuid::new_v4();
+
         Connection {
-            id: Uuid::new_v4(),
-            kind: ConnectionKind::Admin,
+            id,
+            connection_id: id,
+            kind,
+            protocol,
             room_addr,
             last_heartbeat: Instant::now(),
+            closed_deliberately: false,
+            max_message_size: config.max_message_size,
+            rate_limiter: RateLimiter::new(config.rate_limits.clone()),
+            fragmented_message: None,
         }
     }
 
@@ -47,7 +112,7 @@ impl Connection {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.last_heartbeat) > CLIENT_TIMEOUT {
                 println!("Disconnecting because of failed heartbeat");
-                act.send_disconnect_message(act.id);
+                // stopping notifies the room
                 ctx.stop();
                 return;
             }
@@ -56,8 +121,166 @@ impl Connection {
         });
     }
 
-    fn send_disconnect_message(&self, id: Uuid) {
-        self.room_addr.do_send(ClientDisconnect { id });
+    fn send_disconnect_message(&self) {
+        self.room_addr.do_send(ClientDisconnect {
+            id: self.id,
+            connection_id: self.connection_id,
+            closed_deliberately: self.closed_deliberately,
+        });
+    }
+
+    fn write(&self, msg: WebSocketMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        match msg {
+            WebSocketMessage::Text(text) => ctx.text(text),
+            WebSocketMessage::Binary(bin) => ctx.binary(bin),
+            WebSocketMessage::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+        }
+    }
+
+    /// Hands a text or binary message on to the room, which decodes it according to the
+    /// connection's encoding, unless the client is over its rate limits or sent it in the frame
+    /// type the encoding doesn't use.
+    fn handle_payload(
+        &mut self,
+        payload: Vec<u8>,
+        is_text: bool,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let protocol = match &self.protocol {
+            Ok(protocol) => protocol,
+            Err(_) => return,
+        };
+        let message = protocol.encoding.decode(&payload).ok();
+        let message_type = message
+            .as_ref()
+            .and_then(|message| message["type"].as_str());
+
+        match self.rate_limiter.check(message_type) {
+            Verdict::Allowed => (),
+            Verdict::Limited => {
+                println!(
+                    "🐌 Dropping {:?} from {:?}: rate limit hit",
+                    message_type, self.id
+                );
+                metrics::record_rate_limited_message();
+                if !protocol.supports("errors") {
+                    return;
+                }
+                let correlation_id = message
+                    .as_ref()
+                    .and_then(|message| message["correlation_id"].as_str())
+                    .map(String::from);
+                let error = ServerEvent::Error {
+                    code: ErrorCode::RateLimited,
+                    message: "Too many messages, slow down".to_string(),
+                    correlation_id,
+                };
+                let message = protocol.encoding.encode(&error);
+                self.write(message, ctx);
+                return;
+            }
+            Verdict::Exceeded => {
+                println!(
+                    "🚫 Closing connection {:?}: rate limits exceeded repeatedly",
+                    self.id
+                );
+                metrics::record_rate_limit_disconnect();
+                self.closed_deliberately = true;
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("rate limit exceeded".to_string()),
+                }));
+                ctx.stop();
+                return;
+            }
+        }
+
+        if is_text != protocol.encoding.uses_text_frames() {
+            if !protocol.supports("errors") {
+                return;
+            }
+            let frame_type = if is_text { "binary" } else { "text" };
+            let error = ServerEvent::Error {
+                code: protocol.encoding.invalid_payload_code(),
+                message: format!("Messages must be sent in {} frames", frame_type),
+                correlation_id: None,
+            };
+            let message = protocol.encoding.encode(&error);
+            self.write(message, ctx);
+            return;
+        }
+
+        self.room_addr.do_send(ClientMessage {
+            id: self.id,
+            payload,
+        });
+    }
+
+    /// Buffers a continuation frame and hands the message on once its last frame arrived.
+    fn handle_continuation(&mut self, item: Item, ctx: &mut ws::WebsocketContext<Self>) {
+        // the codec already rejects continuation frames that arrive out of order
+        let is_text = matches!(item, Item::FirstText(_));
+        let (data, is_last) = match item {
+            Item::FirstText(data) | Item::FirstBinary(data) => {
+                self.fragmented_message = Some(FragmentedMessage {
+                    is_text,
+                    data: BytesMut::new(),
+                });
+                (data, false)
+            }
+            Item::Continue(data) => (data, false),
+            Item::Last(data) => (data, true),
+        };
+
+        let message = match self.fragmented_message.as_mut() {
+            Some(message) => message,
+            None => return,
+        };
+        if message.data.len() + data.len() > self.max_message_size {
+            self.fragmented_message = None;
+            return self.close_on_protocol_error(ws::ProtocolError::Overflow, ctx);
+        }
+        message.data.extend_from_slice(&data);
+
+        if !is_last {
+            return;
+        }
+        let message = self.fragmented_message.take().unwrap();
+        if message.is_text {
+            if let Err(e) = std::str::from_utf8(&message.data) {
+                return self.close_on_protocol_error(
+                    ws::ProtocolError::Io(io::Error::new(io::ErrorKind::InvalidData, e)),
+                    ctx,
+                );
+            }
+        }
+        self.handle_payload(message.data.to_vec(), message.is_text, ctx);
+    }
+
+    /// Closes the socket with the close code matching the error; stopping notifies the room just
+    /// like any other disconnect.
+    fn close_on_protocol_error(
+        &mut self,
+        error: ws::ProtocolError,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let kind = protocol_error_kind(&error);
+        println!(
+            "⚠️ Closing connection {:?} after protocol error: {}",
+            self.id, error
+        );
+        metrics::record_protocol_error(kind);
+        // the client can't resume a session it was thrown out of
+        self.closed_deliberately = true;
+
+        ctx.close(Some(ws::CloseReason {
+            code: close_code(kind),
+            description: Some(error.to_string()),
+        }));
+        ctx.stop();
     }
 }
 
@@ -71,49 +294,102 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Connection {
             Ok(ws::Message::Pong(_)) => {
                 self.last_heartbeat = Instant::now();
             }
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Binary(bin)) => self.handle_payload(bin.to_vec(), false, ctx),
             Ok(ws::Message::Close(reason)) => {
+                self.closed_deliberately = true;
                 ctx.close(reason);
                 ctx.stop();
             }
-            Ok(ws::Message::Continuation(_)) => {
-                ctx.stop();
-            }
+            Ok(ws::Message::Continuation(item)) => self.handle_continuation(item, ctx),
             Ok(ws::Message::Nop) => (),
-            Ok(ws::Message::Text(text)) => match self.kind {
-                ConnectionKind::User(_) => {
-                    self.room_addr.do_send(ClientMessage {
-                        id: self.id,
-                        payload: text.to_string(),
-                    });
-                }
-                ConnectionKind::Admin => (),
-            },
-            Err(e) => panic!("Error! {:?}", e),
+            Ok(ws::Message::Text(text)) => self.handle_payload(text.as_bytes().to_vec(), true, ctx),
+            Err(e) => self.close_on_protocol_error(e, ctx),
         }
     }
 }
 
+fn protocol_error_kind(error: &ws::ProtocolError) -> ProtocolErrorKind {
+    match error {
+        ws::ProtocolError::Overflow => ProtocolErrorKind::Oversized,
+        ws::ProtocolError::Io(e) if is_invalid_utf8(e) => ProtocolErrorKind::InvalidUtf8,
+        ws::ProtocolError::Io(_) => ProtocolErrorKind::Io,
+        _ => ProtocolErrorKind::Malformed,
+    }
+}
+
+/// Whether the error stands for a text message that isn't valid UTF-8. Messages reassembled from
+/// continuation frames are checked by the connection itself, which keeps the `Utf8Error`, while
+/// actix-web-actors checks single text frames and only passes on the `Utf8Error`'s message.
+fn is_invalid_utf8(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::InvalidData => error.get_ref().is_some_and(|inner| inner.is::<Utf8Error>()),
+        io::ErrorKind::Other => {
+            let message = error.to_string();
+            message.starts_with("invalid utf-8 sequence")
+                || message.starts_with("incomplete utf-8 byte sequence")
+        }
+        _ => false,
+    }
+}
+
+fn close_code(kind: ProtocolErrorKind) -> ws::CloseCode {
+    match kind {
+        ProtocolErrorKind::Oversized => ws::CloseCode::Size,
+        ProtocolErrorKind::InvalidUtf8 => ws::CloseCode::Invalid,
+        ProtocolErrorKind::Malformed => ws::CloseCode::Protocol,
+        ProtocolErrorKind::Io => ws::CloseCode::Error,
+    }
+}
+
 impl Actor for Connection {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        let protocol = match &self.protocol {
+            Ok(protocol) => protocol,
+            Err(reason) => {
+                println!(
+                    "🚫 Closing incompatible connection {:?}: {}",
+                    self.id, reason
+                );
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Protocol,
+                    description: Some(reason.clone()),
+                }));
+                ctx.stop();
+                return;
+            }
+        };
+
         self.heartbeat(ctx);
 
         let addr = ctx.address();
         let message = match &self.kind {
-            ConnectionKind::User(name) => {
-                ClientConnect::user(addr.recipient(), self.id, name.clone())
+            ConnectionKind::User {
+                name,
+                resume_token,
+                side,
+                profile,
+            } => ClientConnect::user(
+                addr.recipient(),
+                self.id,
+                protocol.clone(),
+                name.clone(),
+                resume_token.clone(),
+                *side,
+                profile.clone(),
+            ),
+            ConnectionKind::Admin => {
+                ClientConnect::admin(addr.recipient(), self.id, protocol.clone())
             }
-            ConnectionKind::Admin => ClientConnect::admin(addr.recipient(), self.id),
         };
 
         self.room_addr
             .send(message)
             .into_actor(self)
-            .then(|res, _, ctx| {
+            .then(|res, act, ctx| {
                 match res {
-                    Ok(_res) => (),
+                    Ok(id) => act.id = id,
                     _ => ctx.stop(),
                 }
                 fut::ready(())
@@ -122,7 +398,10 @@ impl Actor for Connection {
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        self.send_disconnect_message(self.id);
+        // incompatible clients never joined the room
+        if self.protocol.is_ok() {
+            self.send_disconnect_message();
+        }
         Running::Stop
     }
 }
@@ -131,6 +410,203 @@ impl Handler<WebSocketMessage> for Connection {
     type Result = ();
 
     fn handle(&mut self, msg: WebSocketMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        self.write(msg, ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{close_code, protocol_error_kind, Connection};
+    use crate::config::Config;
+    use crate::encoding::Encoding;
+    use crate::handshake::ClientProtocol;
+    use crate::matching::{MatchStrategyKind, Profile};
+    use crate::messages::GetRoomState;
+    use crate::room::Room;
+    use actix::{clock::timeout, prelude::Stream, Actor, Addr};
+    use actix_web::web::Bytes;
+    use actix_web_actors::ws::{CloseCode, ProtocolError, WebsocketContext};
+    use serde_json::{json, Value};
+    use std::future::poll_fn;
+    use std::pin::Pin;
+    use std::time::Duration;
+
+    const OPCODE_CONTINUATION: u8 = 0x0;
+    const OPCODE_TEXT: u8 = 0x1;
+    const OPCODE_BINARY: u8 = 0x2;
+    const OPCODE_CLOSE: u8 = 0x8;
+
+    /// A masked client frame; the all-zero mask leaves the payload as it is.
+    fn client_frame(is_last: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        assert!(payload.len() < 126);
+        let mut frame = vec![
+            if is_last { 0x80 } else { 0 } | opcode,
+            0x80 | payload.len() as u8,
+        ];
+        frame.extend_from_slice(&[0; 4]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Splits unmasked server output into the opcodes and payloads of its frames.
+    fn server_frames(mut output: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while output.len() >= 2 {
+            let (len, header_len) = match output[1] & 0x7f {
+                126 => (u16::from_be_bytes([output[2], output[3]]) as usize, 4),
+                127 => (
+                    u64::from_be_bytes(output[2..10].try_into().unwrap()) as usize,
+                    10,
+                ),
+                len => (len as usize, 2),
+            };
+            frames.push((
+                output[0] & 0x0f,
+                output[header_len..header_len + len].to_vec(),
+            ));
+            output = &output[header_len + len..];
+        }
+        frames
+    }
+
+    /// Runs a user connection speaking `protocol` on the raw frames `input`, returning the
+    /// frames the server wrote.
+    async fn run_connection(input: Vec<u8>, protocol: ClientProtocol) -> Vec<(u8, Vec<u8>)> {
+        run_connection_in(&start_room(), input, protocol).await
+    }
+
+    fn start_room() -> Addr<Room> {
+        Room::new(
+            "test".to_string(),
+            None,
+            MatchStrategyKind::default(),
+            &Config::default(),
+        )
+        .start()
+    }
+
+    async fn run_connection_in(
+        room: &Addr<Room>,
+        input: Vec<u8>,
+        protocol: ClientProtocol,
+    ) -> Vec<(u8, Vec<u8>)> {
+        let config = Config::default();
+        let connection = Connection::user(
+            "test".to_string(),
+            None,
+            None,
+            Profile::default(),
+            room.clone(),
+            Ok(protocol),
+            &config,
+        );
+        let (mut sender, payload) = actix_http::h1::Payload::create(false);
+        sender.feed_data(Bytes::from(input));
+        let mut output = WebsocketContext::create(connection, payload);
+
+        let mut written = Vec::new();
+        while let Ok(Some(Ok(bytes))) = timeout(
+            Duration::from_millis(200),
+            poll_fn(|cx| Pin::new(&mut output).poll_next(cx)),
+        )
+        .await
+        {
+            written.extend_from_slice(&bytes);
+        }
+
+        server_frames(&written)
+    }
+
+    /// The close code the server closed the connection with, if any.
+    async fn close_code_for_input(input: Vec<u8>) -> Option<u16> {
+        run_connection(input, ClientProtocol::default())
+            .await
+            .into_iter()
+            .find(|(opcode, _)| *opcode == OPCODE_CLOSE)
+            .map(|(_, payload)| u16::from_be_bytes([payload[0], payload[1]]))
+    }
+
+    #[test]
+    fn it_picks_close_codes_for_protocol_errors() {
+        let close_code_for = |error| close_code(protocol_error_kind(&error));
+
+        assert_eq!(close_code_for(ProtocolError::Overflow), CloseCode::Size);
+        assert_eq!(
+            close_code_for(ProtocolError::UnmaskedFrame),
+            CloseCode::Protocol
+        );
+    }
+
+    #[actix::test]
+    async fn it_closes_connections_sending_invalid_utf8() {
+        let invalid_utf8 = [b'{', 0xff, b'}'];
+
+        let input = client_frame(true, OPCODE_TEXT, &invalid_utf8);
+        assert_eq!(close_code_for_input(input).await, Some(1007));
+
+        let mut input = client_frame(false, OPCODE_TEXT, &invalid_utf8[..1]);
+        input.extend(client_frame(true, OPCODE_CONTINUATION, &invalid_utf8[1..]));
+        assert_eq!(close_code_for_input(input).await, Some(1007));
+
+        let input = client_frame(true, OPCODE_TEXT, br#"{"type":"ready-to-match"}"#);
+        assert_eq!(close_code_for_input(input).await, None);
+    }
+
+    #[actix::test]
+    async fn it_rejects_frames_of_the_type_the_encoding_does_not_use() {
+        let message = br#"{"type":"ready-to-match"}"#;
+        let errors = |frames: Vec<(u8, Vec<u8>)>, opcode: u8| -> Vec<Value> {
+            frames
+                .into_iter()
+                .filter(|(frame_opcode, _)| *frame_opcode == opcode)
+                .map(|(_, payload)| match opcode {
+                    OPCODE_TEXT => serde_json::from_slice(&payload).unwrap(),
+                    _ => rmp_serde::from_slice(&payload).unwrap(),
+                })
+                .filter(|event: &Value| event["type"] == "error")
+                .collect()
+        };
+
+        let frames = run_connection(
+            client_frame(true, OPCODE_BINARY, message),
+            ClientProtocol::default(),
+        )
+        .await;
+        assert_eq!(
+            errors(frames, OPCODE_TEXT)[0]["data"]["code"],
+            json!("invalid_json")
+        );
+
+        let msgpack = ClientProtocol {
+            encoding: Encoding::MessagePack,
+            ..ClientProtocol::default()
+        };
+        let frames = run_connection(client_frame(true, OPCODE_TEXT, message), msgpack).await;
+        assert_eq!(
+            errors(frames, OPCODE_BINARY)[0]["data"]["code"],
+            json!("invalid_message_pack")
+        );
+    }
+
+    #[actix::test]
+    async fn it_ends_the_session_of_connections_the_server_closes() {
+        let closes =
+            |frames: Vec<(u8, Vec<u8>)>| frames.iter().any(|(opcode, _)| *opcode == OPCODE_CLOSE);
+
+        let room = start_room();
+        let input = client_frame(true, OPCODE_TEXT, &[0xff]);
+        assert!(closes(
+            run_connection_in(&room, input, ClientProtocol::default()).await
+        ));
+        assert_eq!(room.send(GetRoomState).await.unwrap().users, 0);
+
+        let room = start_room();
+        let input = (0..20)
+            .flat_map(|_| client_frame(true, OPCODE_TEXT, br#"{"type":"skip-match"}"#))
+            .collect();
+        assert!(closes(
+            run_connection_in(&room, input, ClientProtocol::default()).await
+        ));
+        assert_eq!(room.send(GetRoomState).await.unwrap().users, 0);
     }
 }
@@ -0,0 +1,126 @@
+//! How messages are encoded on the wire, negotiated per connection via the
+//! `Sec-WebSocket-Protocol` header. Clients that don't ask for a subprotocol get JSON.
+
+use actix_web::{http::header, HttpRequest};
+use serde_json::Value;
+
+use crate::messages::WebSocketMessage;
+use crate::protocol::{ErrorCode, ServerEvent};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON in text frames.
+    #[default]
+    Json,
+    /// MessagePack in binary frames, with the same structure as the JSON messages.
+    MessagePack,
+}
+
+impl Encoding {
+    /// The subprotocols the server accepts, in the order it prefers them.
+    pub const SUBPROTOCOLS: [&'static str; 2] = ["chat.v1.json", "chat.v1.msgpack"];
+
+    fn from_subprotocol(subprotocol: &str) -> Option<Encoding> {
+        match subprotocol {
+            "chat.v1.json" => Some(Encoding::Json),
+            "chat.v1.msgpack" => Some(Encoding::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// Picks the first subprotocol the client asked for that the server supports, just like the
+    /// WebSocket handshake does when answering with `SUBPROTOCOLS`.
+    pub fn negotiate(req: &HttpRequest) -> Encoding {
+        req.headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .find_map(|subprotocol| Encoding::from_subprotocol(subprotocol.trim()))
+            .unwrap_or_default()
+    }
+
+    /// Whether messages in this encoding are sent in text frames rather than binary ones.
+    pub fn uses_text_frames(self) -> bool {
+        self == Encoding::Json
+    }
+
+    pub fn encode(self, event: &ServerEvent) -> WebSocketMessage {
+        match self {
+            Encoding::Json => WebSocketMessage::Text(serde_json::to_string_pretty(event).unwrap()),
+            Encoding::MessagePack => {
+                let mut buffer = Vec::new();
+                // human readable so ids are sent as strings just like in JSON
+                let mut serializer = rmp_serde::Serializer::new(&mut buffer)
+                    .with_struct_map()
+                    .with_human_readable();
+                serde::Serialize::serialize(event, &mut serializer).unwrap();
+                WebSocketMessage::Binary(buffer)
+            }
+        }
+    }
+
+    pub fn decode(self, payload: &[u8]) -> Result<Value, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(payload).map_err(|error| error.to_string()),
+            Encoding::MessagePack => {
+                rmp_serde::from_slice(payload).map_err(|error| error.to_string())
+            }
+        }
+    }
+
+    /// The error code for messages that can't be decoded at all.
+    pub fn invalid_payload_code(self) -> ErrorCode {
+        match self {
+            Encoding::Json => ErrorCode::InvalidJson,
+            Encoding::MessagePack => ErrorCode::InvalidMessagePack,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding;
+    use crate::messages::WebSocketMessage;
+    use crate::protocol::ServerEvent;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+    use uuid::{uuid, Uuid};
+
+    const USER_ID: Uuid = uuid!("11111111-06c9-4f14-bf8b-fafce92d6396");
+
+    #[test]
+    fn it_negotiates_the_first_supported_subprotocol() {
+        let negotiate = |subprotocols: &str| {
+            Encoding::negotiate(
+                &TestRequest::default()
+                    .insert_header(("Sec-WebSocket-Protocol", subprotocols))
+                    .to_http_request(),
+            )
+        };
+
+        assert_eq!(
+            negotiate("chat.v2, chat.v1.msgpack, chat.v1.json"),
+            Encoding::MessagePack
+        );
+        assert_eq!(negotiate("chat.v2"), Encoding::Json);
+        assert_eq!(
+            Encoding::negotiate(&TestRequest::default().to_http_request()),
+            Encoding::Json
+        );
+    }
+
+    #[test]
+    fn it_encodes_events_with_the_same_structure_in_message_pack() {
+        let event = ServerEvent::UserLeft { id: USER_ID };
+
+        let payload = match Encoding::MessagePack.encode(&event) {
+            WebSocketMessage::Binary(payload) => payload,
+            _ => panic!(),
+        };
+        assert_eq!(
+            Encoding::MessagePack.decode(&payload).unwrap(),
+            json!({ "type": "user-left", "data": { "id": USER_ID } })
+        );
+    }
+}
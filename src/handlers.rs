@@ -1,11 +1,24 @@
-use actix::Actor;
-use actix_web::{get, web, web::Data, Error, HttpRequest, HttpResponse, Result};
+use actix::{Actor, Addr};
+use actix_web::{
+    get,
+    http::{header, StatusCode},
+    web,
+    web::Data,
+    Error, HttpRequest, HttpResponse, Result,
+};
 use actix_web_actors::ws;
 use serde::{Deserialize, Serialize};
-use uuid::{uuid, Uuid};
+use uuid::Uuid;
 
 use crate::connections::Connection;
-use crate::room::Room;
+use crate::encoding::Encoding;
+use crate::handshake::{ClientProtocol, VersionParams};
+use crate::matching::{MatchStrategyKind, MediaPreference, Profile};
+use crate::messages::{Announce, CloseRoom, GetRoomState, UpdateRoom, VerifyAdminToken};
+use crate::metrics;
+use crate::protocol::{Severity, Side};
+use crate::room::{Announcement, Room, RoomSettingsUpdate, RoomState, MAX_POD_SIZE};
+use crate::util::unix_timestamp;
 use crate::AppState;
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -18,54 +31,316 @@ pub struct RoomInfo {
     pub name: String,
     pub admin_token: Option<String>,
     pub id: Option<Uuid>,
+    pub expires_at: Option<u64>,
+    /// How users are paired up; can only be chosen when the room is created.
+    #[serde(default)]
+    pub match_strategy: MatchStrategyKind,
 }
 
 pub async fn create_room(
     room_data: web::Json<RoomData>,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse> {
+    let expires_at = room_data.attributes.expires_at;
+    if expires_at.is_some_and(|expires_at| expires_at <= unix_timestamp()) {
+        return Ok(error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "expires_at must be in the future",
+        ));
+    }
+
     let mut rooms = data.rooms.lock().unwrap();
-    let new_room = Room::new(room_data.attributes.name.clone());
+    let new_room = Room::new(
+        room_data.attributes.name.clone(),
+        expires_at,
+        room_data.attributes.match_strategy,
+        &data.config,
+    );
 
     let response = RoomData {
         attributes: RoomInfo {
             id: Some(new_room.id),
             name: new_room.name.clone(),
             admin_token: Some(new_room.admin_token.clone()),
+            expires_at: new_room.expires_at,
+            match_strategy: room_data.attributes.match_strategy,
         },
     };
 
-    let new_room_id = new_room.id.clone();
+    let new_room_id = new_room.id;
     let new_room_addr = new_room.start();
     rooms.insert(new_room_id, new_room_addr.clone());
 
     Ok(HttpResponse::Ok().json(response))
 }
 
+#[derive(Debug, Serialize)]
+pub struct RoomStateData {
+    pub attributes: RoomState,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomUpdateData {
+    pub attributes: RoomUpdateInfo,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomUpdateInfo {
+    pub name: Option<String>,
+    pub settings: Option<RoomSettingsUpdate>,
+}
+
+pub async fn list_rooms(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let room_addrs: Vec<Addr<Room>> = data.rooms.lock().unwrap().values().cloned().collect();
+
+    let mut response = Vec::with_capacity(room_addrs.len());
+    for room_addr in room_addrs {
+        // rooms that are shutting down concurrently are simply left out
+        if let Ok(state) = room_addr.send(GetRoomState).await {
+            response.push(RoomStateData { attributes: state });
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn get_room(data: web::Data<AppState>, path: web::Path<String>) -> Result<HttpResponse> {
+    let room_addr = match find_room(&data, &path) {
+        Some(room_addr) => room_addr,
+        None => return Ok(error_response(StatusCode::NOT_FOUND, "Room not found")),
+    };
+
+    match room_addr.send(GetRoomState).await {
+        Ok(state) => Ok(HttpResponse::Ok().json(RoomStateData { attributes: state })),
+        Err(_) => Ok(error_response(StatusCode::NOT_FOUND, "Room not found")),
+    }
+}
+
+pub async fn update_room(
+    req: HttpRequest,
+    room_data: web::Json<RoomUpdateData>,
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let room_addr = match find_room(&data, &path) {
+        Some(room_addr) => room_addr,
+        None => return Ok(error_response(StatusCode::NOT_FOUND, "Room not found")),
+    };
+    if let Err(response) = authorize_admin(&req, &room_addr).await {
+        return Ok(response);
+    }
+
+    let RoomUpdateInfo { name, settings } = room_data.into_inner().attributes;
+    let match_duration = settings
+        .as_ref()
+        .and_then(|settings| settings.match_duration);
+    if match_duration == Some(0) {
+        return Ok(error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "match_duration must be at least 1 second",
+        ));
+    }
+    let pod_size = settings.as_ref().and_then(|settings| settings.pod_size);
+    if pod_size.is_some_and(|pod_size| !(2..=MAX_POD_SIZE).contains(&pod_size)) {
+        return Ok(error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("pod_size must be between 2 and {}", MAX_POD_SIZE),
+        ));
+    }
+    match room_addr.send(UpdateRoom { name, settings }).await {
+        Ok(state) => Ok(HttpResponse::Ok().json(RoomStateData { attributes: state })),
+        Err(_) => Ok(error_response(StatusCode::NOT_FOUND, "Room not found")),
+    }
+}
+
+pub async fn delete_room(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let room_addr = match find_room(&data, &path) {
+        Some(room_addr) => room_addr,
+        None => return Ok(error_response(StatusCode::NOT_FOUND, "Room not found")),
+    };
+    if let Err(response) = authorize_admin(&req, &room_addr).await {
+        return Ok(response);
+    }
+
+    data.rooms
+        .lock()
+        .unwrap()
+        .retain(|_, addr| addr != &room_addr);
+    room_addr.do_send(CloseRoom);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnnouncementData {
+    pub attributes: AnnouncementInfo,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnnouncementInfo {
+    pub message: String,
+    #[serde(default)]
+    pub severity: Severity,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnnouncementResponseData {
+    pub attributes: Announcement,
+}
+
+pub async fn create_announcement(
+    req: HttpRequest,
+    announcement_data: web::Json<AnnouncementData>,
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let room_addr = match find_room(&data, &path) {
+        Some(room_addr) => room_addr,
+        None => return Ok(error_response(StatusCode::NOT_FOUND, "Room not found")),
+    };
+    if let Err(response) = authorize_admin(&req, &room_addr).await {
+        return Ok(response);
+    }
+
+    let AnnouncementInfo {
+        message,
+        severity,
+        expires_at,
+    } = announcement_data.into_inner().attributes;
+    let announce = Announce {
+        message,
+        severity,
+        expires_at,
+    };
+    match room_addr.send(announce).await {
+        Ok(Ok(announcement)) => Ok(HttpResponse::Ok().json(AnnouncementResponseData {
+            attributes: announcement,
+        })),
+        Ok(Err(error)) => Ok(error_response(StatusCode::UNPROCESSABLE_ENTITY, &error)),
+        Err(_) => Ok(error_response(StatusCode::NOT_FOUND, "Room not found")),
+    }
+}
+
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorData {
+    pub errors: Vec<ErrorInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorInfo {
+    pub status: String,
+    pub title: String,
+}
+
+impl ErrorData {
+    fn new(status: StatusCode, title: &str) -> ErrorData {
+        ErrorData {
+            errors: vec![ErrorInfo {
+                status: status.as_str().to_string(),
+                title: title.to_string(),
+            }],
+        }
+    }
+}
+
+fn error_response(status: StatusCode, title: &str) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorData::new(status, title))
+}
+
+async fn verify_admin_token(room_addr: &Addr<Room>, token: String) -> bool {
+    room_addr
+        .send(VerifyAdminToken { token })
+        .await
+        .unwrap_or(false)
+}
+
+/// Checks the `Authorization: Bearer <admin_token>` header of a REST request against the room's
+/// admin token, returning the error response to send if it doesn't match.
+async fn authorize_admin(req: &HttpRequest, room_addr: &Addr<Room>) -> Result<(), HttpResponse> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match token {
+        None => Err(error_response(
+            StatusCode::UNAUTHORIZED,
+            "Missing admin token",
+        )),
+        Some(token) => {
+            if verify_admin_token(room_addr, token.to_string()).await {
+                Ok(())
+            } else {
+                println!(
+                    "🚫 Rejected request {} {} from {:?}: invalid admin token",
+                    req.method(),
+                    req.path(),
+                    req.peer_addr()
+                );
+                Err(error_response(StatusCode::FORBIDDEN, "Invalid admin token"))
+            }
+        }
+    }
+}
+
+fn find_room(data: &AppState, room_id: &str) -> Option<Addr<Room>> {
+    let room_id = Uuid::parse_str(room_id).ok()?;
+    let rooms = data.rooms.lock().unwrap();
+    rooms.get(&room_id).cloned()
+}
+
 #[get("/{room_id}/admin/{admin_token}")]
 pub async fn start_admin_connection(
     req: HttpRequest,
     stream: web::Payload,
     data: Data<AppState>,
     path: web::Path<(String, String)>,
+    version_params: web::Query<VersionParams>,
 ) -> Result<HttpResponse, Error> {
-    let (_room_id, _admin_token) = path.into_inner();
-    // TODO: this should be the room_id in the path later on
-    //       also, this should check the token is indeed correct
-    let room_id: Uuid = uuid!("27a64ebc-06c9-4f14-bf8b-fafce92d6396");
-    let mut rooms = data.rooms.lock().unwrap();
-    let room_addr = match rooms.get(&room_id) {
-        Some(room) => room.clone(),
-        None => {
-            let new_room = Room::new("test".to_string()).start();
-            rooms.insert(room_id, new_room.clone());
-            new_room
-        }
+    let (room_id, admin_token) = path.into_inner();
+    let room_addr = match find_room(&data, &room_id) {
+        Some(room_addr) => room_addr,
+        None => return Ok(error_response(StatusCode::NOT_FOUND, "Room not found")),
     };
-    let ws = Connection::admin(room_addr);
 
-    let resp = ws::start(ws, &req, stream)?;
-    Ok(resp)
+    if !verify_admin_token(&room_addr, admin_token).await {
+        println!(
+            "🚫 Rejected admin connection to room {:?} from {:?}: invalid admin token",
+            room_id,
+            req.peer_addr()
+        );
+        return Ok(error_response(StatusCode::FORBIDDEN, "Invalid admin token"));
+    }
+    let protocol = ClientProtocol::negotiate(&req, &version_params);
+    let ws = Connection::admin(room_addr, protocol, &data.config);
+
+    start_websocket(ws, &req, stream, data.config.max_message_size)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectionParams {
+    /// The `resume_token` from an earlier session's `self-joined` event.
+    pub resume_token: Option<String>,
+    /// The group the user belongs to in rooms that only match users across groups.
+    pub side: Option<Side>,
+    /// Comma separated tags describing the user, e.g. their role.
+    pub tags: Option<String>,
+    /// Comma separated topics the user would like to talk about.
+    pub interests: Option<String>,
+    pub language: Option<String>,
+    pub media: Option<MediaPreference>,
 }
 
 #[get("/{room_id}/{name}")]
@@ -74,22 +349,159 @@ pub async fn start_connection(
     stream: web::Payload,
     data: Data<AppState>,
     path: web::Path<(String, String)>,
+    params: web::Query<ConnectionParams>,
+    version_params: web::Query<VersionParams>,
 ) -> Result<HttpResponse, Error> {
-    let (_room_id, name) = path.into_inner();
-    // TODO: this should be the room_id in the path later on
-    let room_id: Uuid = uuid!("27a64ebc-06c9-4f14-bf8b-fafce92d6396");
-    let mut rooms = data.rooms.lock().unwrap();
-    let room_addr = match rooms.get(&room_id) {
-        Some(room) => room.clone(),
-        None => {
-            // TODO: this should later respond with 404
-            let new_room = Room::new("test".to_string()).start();
-            rooms.insert(room_id, new_room.clone());
-            new_room
-        }
+    let (room_id, name) = path.into_inner();
+    let room_addr = match find_room(&data, &room_id) {
+        Some(room_addr) => room_addr,
+        None => return Ok(error_response(StatusCode::NOT_FOUND, "Room not found")),
+    };
+    let params = params.into_inner();
+    let profile = Profile::new(
+        params.tags.as_deref(),
+        params.interests.as_deref(),
+        params.language.as_deref(),
+        params.media,
+    );
+    let ws = Connection::user(
+        name,
+        params.resume_token,
+        params.side,
+        profile,
+        room_addr,
+        ClientProtocol::negotiate(&req, &version_params),
+        &data.config,
+    );
+
+    start_websocket(ws, &req, stream, data.config.max_message_size)
+}
+
+fn start_websocket(
+    connection: Connection,
+    req: &HttpRequest,
+    stream: web::Payload,
+    max_message_size: usize,
+) -> Result<HttpResponse, Error> {
+    // a single frame can't be larger than the whole message it's part of
+    ws::WsResponseBuilder::new(connection, req, stream)
+        .protocols(&Encoding::SUBPROTOCOLS)
+        .frame_size(max_message_size)
+        .start()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_room, delete_room, get_room, update_room};
+    use crate::config::Config;
+    use crate::AppState;
+    use actix_web::{
+        dev::{Service, ServiceResponse},
+        http::StatusCode,
+        test, web, App,
     };
-    let ws = Connection::user(name, room_addr);
+    use serde_json::{json, Value};
+
+    async fn app(
+    ) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>
+    {
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState::new(Config::default())))
+                .route("/rooms", web::post().to(create_room))
+                .route("/rooms/{room_id}", web::get().to(get_room))
+                .route("/rooms/{room_id}", web::patch().to(update_room))
+                .route("/rooms/{room_id}", web::delete().to(delete_room)),
+        )
+        .await
+    }
+
+    /// Creates a room, returning its id and admin token.
+    async fn create(
+        app: &impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+    ) -> (String, String) {
+        let req = test::TestRequest::post()
+            .uri("/rooms")
+            .set_json(json!({ "attributes": { "name": "test" } }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(app, req).await;
+        let attributes = &body["attributes"];
+        (
+            attributes["id"].as_str().unwrap().to_string(),
+            attributes["admin_token"].as_str().unwrap().to_string(),
+        )
+    }
+
+    fn patch(id: &str, token: Option<&str>, attributes: Value) -> actix_http::Request {
+        let mut req = test::TestRequest::patch()
+            .uri(&format!("/rooms/{}", id))
+            .set_json(json!({ "attributes": attributes }));
+        if let Some(token) = token {
+            req = req.insert_header(("Authorization", format!("Bearer {}", token)));
+        }
+        req.to_request()
+    }
+
+    #[actix_web::test]
+    async fn it_requires_the_admin_token_to_change_rooms() {
+        let app = app().await;
+        let (id, token) = create(&app).await;
+
+        let res = test::call_service(&app, patch(&id, None, json!({ "name": "new" }))).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res =
+            test::call_service(&app, patch(&id, Some("wrong"), json!({ "name": "new" }))).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/rooms/{}", id))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let res =
+            test::call_service(&app, patch(&id, Some(&token), json!({ "name": "new" }))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["attributes"]["name"], json!("new"));
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/rooms/{}", id))
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NO_CONTENT
+        );
+        let req = test::TestRequest::get()
+            .uri(&format!("/rooms/{}", id))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn it_rejects_invalid_settings() {
+        let app = app().await;
+        let (id, token) = create(&app).await;
+
+        for settings in [json!({ "match_duration": 0 }), json!({ "pod_size": 1 })] {
+            let req = patch(&id, Some(&token), json!({ "settings": settings }));
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::UNPROCESSABLE_ENTITY
+            );
+        }
 
-    let resp = ws::start(ws, &req, stream)?;
-    Ok(resp)
+        let req = patch(
+            &id,
+            Some(&token),
+            json!({ "settings": { "match_duration": 60 } }),
+        );
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["attributes"]["settings"]["match_duration"], json!(60));
+    }
 }
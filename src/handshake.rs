@@ -0,0 +1,162 @@
+//! What a client and the server agree on when a WebSocket connection is opened: the protocol
+//! version, the capabilities in use and the encoding. Clients state the version and their
+//! feature flags via the `version` and `features` query parameters; clients from before
+//! versioning don't send them and are treated as version 1.
+
+use actix_web::HttpRequest;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::encoding::Encoding;
+
+/// The protocol version this server speaks.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// The oldest protocol version the server still accepts clients of.
+pub const OLDEST_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features the server supports, which clients can ask for via `features`. `msgpack` is
+/// only reported, the encoding is chosen via the `Sec-WebSocket-Protocol` header.
+pub const CAPABILITIES: [&str; 6] = [
+    "resume",
+    "msgpack",
+    "match-roles",
+    "queue-position",
+    "errors",
+    "chat",
+];
+
+#[derive(Debug, Deserialize)]
+pub struct VersionParams {
+    pub version: Option<u32>,
+    /// Comma separated feature flags.
+    pub features: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientProtocol {
+    pub version: u32,
+    /// The capabilities in use for this connection.
+    pub capabilities: Vec<String>,
+    pub encoding: Encoding,
+}
+
+impl ClientProtocol {
+    /// Agrees on a protocol with the client, failing with the reason to close the connection
+    /// with if the client's version isn't supported.
+    pub fn negotiate(req: &HttpRequest, params: &VersionParams) -> Result<ClientProtocol, String> {
+        let version = params.version.unwrap_or(OLDEST_PROTOCOL_VERSION);
+        if !(OLDEST_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(format!(
+                "unsupported protocol version {}, supported versions are {} to {}",
+                version, OLDEST_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ));
+        }
+
+        let encoding = Encoding::negotiate(req);
+        Ok(ClientProtocol {
+            version,
+            capabilities: capabilities(params.features.as_deref(), encoding),
+            encoding,
+        })
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Rewrites a command sent in the client's protocol version into the current one. The only
+    /// command that changed so far is `ready-to-match`; all others are passed on as they are.
+    pub fn upgrade_command(&self, mut payload: Value) -> Value {
+        // version 1 clients named the user to match in `ready-to-match`, which is always the
+        // sender now
+        if self.version == 1 && payload["type"] == "ready-to-match" {
+            if let Some(payload) = payload.as_object_mut() {
+                payload.remove("data");
+            }
+        }
+
+        payload
+    }
+}
+
+impl Default for ClientProtocol {
+    fn default() -> ClientProtocol {
+        ClientProtocol {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities(None, Encoding::default()),
+            encoding: Encoding::default(),
+        }
+    }
+}
+
+/// The capabilities among the comma separated `features` the server supports. Clients that don't
+/// state any feature flags get everything the server offers.
+fn capabilities(features: Option<&str>, encoding: Encoding) -> Vec<String> {
+    let features: Option<Vec<&str>> =
+        features.map(|features| features.split(',').map(str::trim).collect());
+    CAPABILITIES
+        .iter()
+        .filter(|capability| match **capability {
+            "msgpack" => encoding == Encoding::MessagePack,
+            capability => features
+                .as_ref()
+                .is_none_or(|features| features.contains(&capability)),
+        })
+        .map(|capability| capability.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientProtocol, VersionParams, PROTOCOL_VERSION};
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    fn negotiate(version: Option<u32>, features: Option<&str>) -> Result<ClientProtocol, String> {
+        ClientProtocol::negotiate(
+            &TestRequest::default().to_http_request(),
+            &VersionParams {
+                version,
+                features: features.map(String::from),
+            },
+        )
+    }
+
+    #[test]
+    fn it_negotiates_the_version_and_capabilities() {
+        let protocol = negotiate(Some(PROTOCOL_VERSION), Some("errors, resume, video")).unwrap();
+        assert_eq!(protocol.version, PROTOCOL_VERSION);
+        assert_eq!(protocol.capabilities, vec!["resume", "errors"]);
+        assert!(protocol.supports("errors"));
+        assert!(!protocol.supports("chat"));
+        // the encoding isn't negotiated via `features`
+        assert!(!negotiate(Some(PROTOCOL_VERSION), Some("msgpack"))
+            .unwrap()
+            .supports("msgpack"));
+
+        // clients from before versioning
+        assert_eq!(negotiate(None, None).unwrap().version, 1);
+
+        assert!(negotiate(Some(PROTOCOL_VERSION + 1), None).is_err());
+        assert!(negotiate(Some(0), None).is_err());
+    }
+
+    #[test]
+    fn it_upgrades_version_1_commands() {
+        let ready_to_match = json!({
+            "type": "ready-to-match",
+            "data": { "id": "11111111-06c9-4f14-bf8b-fafce92d6396" }
+        });
+
+        assert_eq!(
+            negotiate(Some(1), None)
+                .unwrap()
+                .upgrade_command(ready_to_match.clone()),
+            json!({ "type": "ready-to-match" })
+        );
+        assert_eq!(
+            ClientProtocol::default().upgrade_command(ready_to_match.clone()),
+            ready_to_match
+        );
+    }
+}
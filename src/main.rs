@@ -5,41 +5,63 @@ use std::collections::HashMap;
 use std::sync::Mutex;
 use uuid::Uuid;
 
+mod config;
 mod connections;
+mod encoding;
 mod handlers;
+mod handshake;
+mod matching;
 mod messages;
+mod metrics;
+mod protocol;
+mod rate_limit;
 mod room;
+mod sweeper;
 mod util;
 
+use config::Config;
 use room::Room;
 
 pub struct AppState {
     pub rooms: Mutex<HashMap<Uuid, Addr<Room>>>,
+    pub config: Config,
 }
 
 impl AppState {
-    pub fn new() -> AppState {
+    pub fn new(config: Config) -> AppState {
         AppState {
             rooms: Mutex::new(HashMap::new()),
+            config,
         }
     }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let data = Data::new(AppState::new());
+    let data = Data::new(AppState::new(Config::from_env()));
 
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    actix_web::rt::spawn(sweeper::sweep_rooms(data.clone()));
+
     let bind_to = "127.0.0.1:4000";
     let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .wrap(Cors::permissive())
             .app_data(data.clone())
+            .route("/rooms", web::get().to(handlers::list_rooms))
+            .route("/rooms", web::post().to(handlers::create_room))
+            .route("/rooms/{room_id}", web::get().to(handlers::get_room))
+            .route("/rooms/{room_id}", web::patch().to(handlers::update_room))
+            .route("/rooms/{room_id}", web::delete().to(handlers::delete_room))
+            .route(
+                "/rooms/{room_id}/announcements",
+                web::post().to(handlers::create_announcement),
+            )
+            .route("/metrics", web::get().to(handlers::metrics))
             .service(handlers::start_admin_connection)
             .service(handlers::start_connection)
-            .route("/rooms", web::post().to(handlers::create_room))
     })
     .bind(bind_to)?
     .run();
@@ -0,0 +1,751 @@
+//! Policies for picking a ready user's partner among everyone they could be matched with. Each
+//! room uses the strategy chosen when it was created. Rooms that match everyone at once in rounds
+//! use a round-robin schedule instead, or group users into pods if matches have more than two
+//! users.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::protocol::Side;
+
+/// Whether a user wants to meet with audio only or with video.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MediaPreference {
+    AudioOnly,
+    Video,
+}
+
+/// How many tags and how many interests a profile keeps; the rest are ignored.
+const MAX_PROFILE_ITEMS: usize = 20;
+/// How many characters a tag, interest or language may have; longer ones are ignored.
+const MAX_PROFILE_ITEM_LENGTH: usize = 50;
+
+/// What a user told about themselves when joining, used to find a partner who fits them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub tags: Vec<String>,
+    pub interests: Vec<String>,
+    pub language: Option<String>,
+    pub media: Option<MediaPreference>,
+}
+
+impl Profile {
+    /// Builds a profile from comma separated `tags` and `interests`, ignoring case and
+    /// surrounding whitespace of them and of the `language`. Only the first `MAX_PROFILE_ITEMS`
+    /// distinct tags and interests of up to `MAX_PROFILE_ITEM_LENGTH` characters are kept, so
+    /// scoring users against each other stays cheap.
+    pub fn new(
+        tags: Option<&str>,
+        interests: Option<&str>,
+        language: Option<&str>,
+        media: Option<MediaPreference>,
+    ) -> Profile {
+        let normalize =
+            |value: &str| Some(value.trim().to_lowercase()).filter(|item| is_valid_item(item));
+        let list = |value: Option<&str>| {
+            let mut items: Vec<String> = Vec::new();
+            for item in value.unwrap_or_default().split(',').filter_map(normalize) {
+                if items.len() == MAX_PROFILE_ITEMS {
+                    break;
+                }
+                if !items.contains(&item) {
+                    items.push(item);
+                }
+            }
+            // sorted so shared items can be found by binary search
+            items.sort();
+            items
+        };
+
+        Profile {
+            tags: list(tags),
+            interests: list(interests),
+            language: language.and_then(normalize),
+            media,
+        }
+    }
+
+    /// How well two users fit each other: shared interests count the most, shared tags less. A
+    /// different language or media preference weighs heavily against a match, but only if both
+    /// users stated one.
+    pub fn compatibility(&self, other: &Profile) -> i32 {
+        let shared = |a: &[String], b: &[String]| {
+            a.iter()
+                .filter(|item| b.binary_search(item).is_ok())
+                .count() as i32
+        };
+        let mut score =
+            2 * shared(&self.interests, &other.interests) + shared(&self.tags, &other.tags);
+
+        match (&self.language, &other.language) {
+            (Some(a), Some(b)) if a == b => score += 3,
+            (Some(_), Some(_)) => score -= 10,
+            _ => {}
+        }
+        match (self.media, other.media) {
+            (Some(a), Some(b)) if a == b => score += 2,
+            (Some(_), Some(_)) => score -= 5,
+            _ => {}
+        }
+
+        score
+    }
+}
+
+fn is_valid_item(item: &str) -> bool {
+    !item.is_empty() && item.chars().count() <= MAX_PROFILE_ITEM_LENGTH
+}
+
+/// A user who could be matched with the user looking for a partner: connected, not in a match
+/// and not met before.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub id: Uuid,
+    pub joined_at: Instant,
+    /// When the user joined or their last match ended.
+    pub available_since: Instant,
+    /// How many matches the user had in this room so far.
+    pub match_count: usize,
+    pub side: Option<Side>,
+    pub profile: Profile,
+}
+
+pub trait MatchStrategy: Send {
+    /// Picks `user`'s partner among `candidates`, or `None` to leave them waiting.
+    fn pick_partner(&self, user: &Candidate, candidates: &[Candidate]) -> Option<Uuid>;
+}
+
+/// The user who joined the room first.
+pub struct FirstAvailable;
+
+impl MatchStrategy for FirstAvailable {
+    fn pick_partner(&self, _: &Candidate, candidates: &[Candidate]) -> Option<Uuid> {
+        candidates
+            .iter()
+            .min_by_key(|candidate| candidate.joined_at)
+            .map(|candidate| candidate.id)
+    }
+}
+
+/// Any of the candidates with the same chance.
+pub struct Random;
+
+impl MatchStrategy for Random {
+    fn pick_partner(&self, _: &Candidate, candidates: &[Candidate]) -> Option<Uuid> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        Some(candidates[fastrand::usize(..candidates.len())].id)
+    }
+}
+
+/// The user who has been without a partner the longest.
+pub struct LongestWaiting;
+
+impl MatchStrategy for LongestWaiting {
+    fn pick_partner(&self, _: &Candidate, candidates: &[Candidate]) -> Option<Uuid> {
+        candidates
+            .iter()
+            .min_by_key(|candidate| candidate.available_since)
+            .map(|candidate| candidate.id)
+    }
+}
+
+/// The user who had the fewest matches so far, the longest waiting of them on a tie.
+pub struct LeastMatched;
+
+impl MatchStrategy for LeastMatched {
+    fn pick_partner(&self, _: &Candidate, candidates: &[Candidate]) -> Option<Uuid> {
+        candidates
+            .iter()
+            .min_by_key(|candidate| (candidate.match_count, candidate.available_since))
+            .map(|candidate| candidate.id)
+    }
+}
+
+/// The user whose profile fits best, the longest waiting of them on a tie. In rooms matching
+/// pods, each further member is scored against the user the pod is formed for only, not against
+/// the members picked so far.
+pub struct BestFit;
+
+impl MatchStrategy for BestFit {
+    fn pick_partner(&self, user: &Candidate, candidates: &[Candidate]) -> Option<Uuid> {
+        candidates
+            .iter()
+            .max_by_key(|candidate| {
+                (
+                    user.profile.compatibility(&candidate.profile),
+                    Reverse(candidate.available_since),
+                )
+            })
+            .map(|candidate| candidate.id)
+    }
+}
+
+/// The strategies rooms can be created with, as named in `POST /rooms`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchStrategyKind {
+    #[default]
+    FirstAvailable,
+    Random,
+    LongestWaiting,
+    LeastMatched,
+    BestFit,
+}
+
+impl MatchStrategyKind {
+    pub fn strategy(self) -> Box<dyn MatchStrategy> {
+        match self {
+            MatchStrategyKind::FirstAvailable => Box::new(FirstAvailable),
+            MatchStrategyKind::Random => Box::new(Random),
+            MatchStrategyKind::LongestWaiting => Box::new(LongestWaiting),
+            MatchStrategyKind::LeastMatched => Box::new(LeastMatched),
+            MatchStrategyKind::BestFit => Box::new(BestFit),
+        }
+    }
+}
+
+/// Whether `a` and `b` were in a match together before.
+pub fn have_met(previous_matches: &[Vec<Uuid>], a: &Uuid, b: &Uuid) -> bool {
+    previous_matches
+        .iter()
+        .any(|users| users.contains(a) && users.contains(b))
+}
+
+/// One round of a round-robin schedule.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Round {
+    pub pairs: Vec<(Uuid, Uuid)>,
+    /// The user sitting the round out when the number of users is odd.
+    pub bye: Option<Uuid>,
+}
+
+/// Schedules rounds in which every user meets every other user exactly once, using the circle
+/// method: the first user stays in place while the others rotate around them, and each user is
+/// paired with the one opposite. With an odd number of users, whoever is opposite the empty
+/// seat gets a bye.
+pub fn round_robin(ids: &[Uuid]) -> Vec<Round> {
+    let mut seats: Vec<Option<Uuid>> = ids.iter().copied().map(Some).collect();
+    if seats.len() % 2 == 1 {
+        seats.push(None);
+    }
+    let seat_count = seats.len();
+
+    let mut rounds = Vec::new();
+    for _ in 1..seat_count {
+        let mut round = Round {
+            pairs: Vec::new(),
+            bye: None,
+        };
+        for i in 0..seat_count / 2 {
+            match (seats[i], seats[seat_count - 1 - i]) {
+                (Some(a), Some(b)) => round.pairs.push((a, b)),
+                (Some(id), None) | (None, Some(id)) => round.bye = Some(id),
+                (None, None) => {}
+            }
+        }
+        rounds.push(round);
+        seats[1..].rotate_right(1);
+    }
+
+    rounds
+}
+
+/// Pairs up as many of `ids` as possible with someone they haven't met. Starts from the round of
+/// a round-robin schedule with the most new pairs, which has only new ones if the same users took
+/// part in all previous rounds, and grows it into a maximum pairing.
+pub fn next_round_pairs(ids: &[Uuid], previous_matches: &[Vec<Uuid>]) -> Vec<(Uuid, Uuid)> {
+    let round = round_robin(ids)
+        .into_iter()
+        .map(|round| {
+            round
+                .pairs
+                .into_iter()
+                .filter(|(a, b)| !have_met(previous_matches, a, b))
+                .collect::<Vec<_>>()
+        })
+        .max_by_key(|pairs| pairs.len())
+        .unwrap_or_default();
+
+    maximum_pairing(ids, &round, |a, b| !have_met(previous_matches, a, b))
+}
+
+/// Grows the `initial` pairs among `ids` into as many pairs as possible of users `can_pair`
+/// allows to be paired. Users who are paired initially stay paired, though possibly with someone
+/// else, so that more users can be.
+fn maximum_pairing(
+    ids: &[Uuid],
+    initial: &[(Uuid, Uuid)],
+    can_pair: impl Fn(&Uuid, &Uuid) -> bool,
+) -> Vec<(Uuid, Uuid)> {
+    let adjacent: Vec<Vec<usize>> = ids
+        .iter()
+        .map(|a| {
+            (0..ids.len())
+                .filter(|j| ids[*j] != *a && can_pair(a, &ids[*j]))
+                .collect()
+        })
+        .collect();
+    let index = |id: &Uuid| ids.iter().position(|other_id| other_id == id);
+
+    let mut matching = Blossom::new(&adjacent);
+    for (a, b) in initial {
+        if let (Some(a), Some(b)) = (index(a), index(b)) {
+            matching.mate[a] = Some(b);
+            matching.mate[b] = Some(a);
+        }
+    }
+    // a user without an augmenting path now won't get one once others are paired either
+    for root in 0..ids.len() {
+        if matching.mate[root].is_none() {
+            if let Some(end) = matching.find_augmenting_path(root) {
+                matching.augment(end);
+            }
+        }
+    }
+
+    (0..ids.len())
+        .filter_map(|a| match matching.mate[a] {
+            Some(b) if a < b => Some((ids[a], ids[b])),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Edmonds' blossom algorithm for a maximum matching in a graph given by its adjacency lists.
+/// Searches for paths alternating between unmatched and matched edges from an unmatched vertex
+/// to another one, contracting odd cycles ("blossoms") into their base on the way; flipping
+/// such a path matches one more pair.
+struct Blossom<'a> {
+    adjacent: &'a [Vec<usize>],
+    mate: Vec<Option<usize>>,
+    /// The vertex each vertex was reached from in the current search.
+    parent: Vec<Option<usize>>,
+    /// The base of the blossom each vertex is contracted into, itself if none.
+    base: Vec<usize>,
+    /// Whether a vertex is in the search tree at an even distance from the root.
+    reached: Vec<bool>,
+    in_blossom: Vec<bool>,
+}
+
+impl Blossom<'_> {
+    fn new(adjacent: &[Vec<usize>]) -> Blossom<'_> {
+        let n = adjacent.len();
+        Blossom {
+            adjacent,
+            mate: vec![None; n],
+            parent: vec![None; n],
+            base: (0..n).collect(),
+            reached: vec![false; n],
+            in_blossom: vec![false; n],
+        }
+    }
+
+    /// Finds an unmatched vertex an augmenting path from `root` ends at, if there's any.
+    fn find_augmenting_path(&mut self, root: usize) -> Option<usize> {
+        let n = self.adjacent.len();
+        self.parent = vec![None; n];
+        self.base = (0..n).collect();
+        self.reached = vec![false; n];
+        self.reached[root] = true;
+        let mut queue = VecDeque::from([root]);
+
+        while let Some(v) = queue.pop_front() {
+            for &to in &self.adjacent[v] {
+                if self.base[v] == self.base[to] || self.mate[v] == Some(to) {
+                    continue;
+                }
+                let to_is_even =
+                    to == root || self.mate[to].is_some_and(|mate| self.parent[mate].is_some());
+                if to_is_even {
+                    // an odd cycle, contract it into its base
+                    let blossom_base = self.lowest_common_ancestor(v, to);
+                    self.in_blossom = vec![false; n];
+                    self.mark_path(v, blossom_base, to);
+                    self.mark_path(to, blossom_base, v);
+                    for i in 0..n {
+                        if self.in_blossom[self.base[i]] {
+                            self.base[i] = blossom_base;
+                            if !self.reached[i] {
+                                self.reached[i] = true;
+                                queue.push_back(i);
+                            }
+                        }
+                    }
+                } else if self.parent[to].is_none() {
+                    self.parent[to] = Some(v);
+                    match self.mate[to] {
+                        None => return Some(to),
+                        Some(mate) => {
+                            self.reached[mate] = true;
+                            queue.push_back(mate);
+                        }
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    fn lowest_common_ancestor(&self, mut a: usize, mut b: usize) -> usize {
+        let mut on_path = vec![false; self.adjacent.len()];
+        loop {
+            a = self.base[a];
+            on_path[a] = true;
+            match self.mate[a] {
+                Some(mate) => a = self.parent[mate].unwrap(),
+                None => break,
+            }
+        }
+        loop {
+            b = self.base[b];
+            if on_path[b] {
+                return b;
+            }
+            b = self.parent[self.mate[b].unwrap()].unwrap();
+        }
+    }
+
+    /// Marks the blossoms on the path from `v` down to the blossom's `base`, pointing the path's
+    /// vertices towards `child` so the path can be walked from either side of the cycle.
+    fn mark_path(&mut self, mut v: usize, base: usize, mut child: usize) {
+        while self.base[v] != base {
+            let mate = self.mate[v].unwrap();
+            self.in_blossom[self.base[v]] = true;
+            self.in_blossom[self.base[mate]] = true;
+            self.parent[v] = Some(child);
+            child = mate;
+            v = self.parent[mate].unwrap();
+        }
+    }
+
+    /// Flips the augmenting path ending at `end`.
+    fn augment(&mut self, end: usize) {
+        let mut v = Some(end);
+        while let Some(current) = v {
+            let parent = self.parent[current].unwrap();
+            let next = self.mate[parent];
+            self.mate[current] = Some(parent);
+            self.mate[parent] = Some(current);
+            v = next;
+        }
+    }
+}
+
+/// Pairs up as many users of side `a` as possible with users of side `b` they haven't met.
+/// Rotating the longer side past the shorter one lets everyone on the shorter side meet everyone
+/// on the longer side in as many rounds as the longer side has users. Picks the rotation with the
+/// most new pairs and grows them into a maximum matching (see `maximum_pairing`) for whoever is
+/// left over.
+pub fn next_bipartite_round_pairs(
+    a: &[Uuid],
+    b: &[Uuid],
+    previous_matches: &[Vec<Uuid>],
+) -> Vec<(Uuid, Uuid)> {
+    let (longer, shorter) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let pairs: Vec<(Uuid, Uuid)> = (0..longer.len())
+        .map(|rotation| {
+            shorter
+                .iter()
+                .enumerate()
+                .map(|(i, id)| (longer[(i + rotation) % longer.len()], *id))
+                .filter(|(a, b)| !have_met(previous_matches, a, b))
+                .collect::<Vec<_>>()
+        })
+        .max_by_key(|pairs| pairs.len())
+        .unwrap_or_default();
+
+    // longer comes first, so the pairs keep a user of the longer side first
+    let ids: Vec<Uuid> = longer.iter().chain(shorter).copied().collect();
+    maximum_pairing(&ids, &pairs, |a, b| {
+        longer.contains(a) != longer.contains(b) && !have_met(previous_matches, a, b)
+    })
+}
+
+/// Splits `ids` into pods of `pod_size` users, filling each pod with the users who met the
+/// fewest of its members before. The last pod is smaller if the users don't divide evenly, and a
+/// single user left over isn't put in any pod.
+pub fn group_into_pods(
+    ids: &[Uuid],
+    pod_size: usize,
+    previous_matches: &[Vec<Uuid>],
+) -> Vec<Vec<Uuid>> {
+    let mut remaining = ids.to_vec();
+    let mut pods = Vec::new();
+    while remaining.len() >= 2 {
+        let size = pod_size.min(remaining.len());
+        let mut pod = vec![remaining.remove(0)];
+        while pod.len() < size {
+            let (index, _) = remaining
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, id)| {
+                    pod.iter()
+                        .filter(|member| have_met(previous_matches, member, id))
+                        .count()
+                })
+                .expect("there are enough users left to fill the pod");
+            pod.push(remaining.remove(index));
+        }
+        pods.push(pod);
+    }
+
+    pods
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        group_into_pods, have_met, maximum_pairing, next_bipartite_round_pairs, next_round_pairs,
+        round_robin, BestFit, Candidate, FirstAvailable, LeastMatched, LongestWaiting,
+        MatchStrategy, MediaPreference, Profile, Random,
+    };
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+    use uuid::{uuid, Uuid};
+
+    const USER1_ID: Uuid = uuid!("11111111-06c9-4f14-bf8b-fafce92d6396");
+    const USER2_ID: Uuid = uuid!("22222222-06c9-4f14-bf8b-fafce92d6396");
+    const USER3_ID: Uuid = uuid!("33333333-06c9-4f14-bf8b-fafce92d6396");
+    const USER4_ID: Uuid = uuid!("44444444-06c9-4f14-bf8b-fafce92d6396");
+    const USER5_ID: Uuid = uuid!("55555555-06c9-4f14-bf8b-fafce92d6396");
+    const USER6_ID: Uuid = uuid!("66666666-06c9-4f14-bf8b-fafce92d6396");
+
+    fn user() -> Candidate {
+        Candidate {
+            id: USER1_ID,
+            joined_at: Instant::now(),
+            available_since: Instant::now(),
+            match_count: 0,
+            side: None,
+            profile: Profile::default(),
+        }
+    }
+
+    /// User 2 joined first but just finished a match, user 3 joined later and never had one.
+    fn candidates() -> Vec<Candidate> {
+        let now = Instant::now();
+        vec![
+            Candidate {
+                id: USER3_ID,
+                joined_at: now + Duration::from_secs(10),
+                available_since: now + Duration::from_secs(10),
+                match_count: 0,
+                side: None,
+                profile: Profile::default(),
+            },
+            Candidate {
+                id: USER2_ID,
+                joined_at: now,
+                available_since: now + Duration::from_secs(60),
+                match_count: 2,
+                side: None,
+                profile: Profile::default(),
+            },
+        ]
+    }
+
+    #[test]
+    fn it_picks_partners_by_strategy() {
+        let candidates = candidates();
+
+        assert_eq!(
+            FirstAvailable.pick_partner(&user(), &candidates),
+            Some(USER2_ID)
+        );
+        assert_eq!(
+            LongestWaiting.pick_partner(&user(), &candidates),
+            Some(USER3_ID)
+        );
+        assert_eq!(
+            LeastMatched.pick_partner(&user(), &candidates),
+            Some(USER3_ID)
+        );
+        assert!(Random
+            .pick_partner(&user(), &candidates)
+            .is_some_and(|id| id == USER2_ID || id == USER3_ID));
+    }
+
+    #[test]
+    fn it_leaves_users_without_candidates_waiting() {
+        assert_eq!(FirstAvailable.pick_partner(&user(), &[]), None);
+        assert_eq!(Random.pick_partner(&user(), &[]), None);
+        assert_eq!(LongestWaiting.pick_partner(&user(), &[]), None);
+        assert_eq!(LeastMatched.pick_partner(&user(), &[]), None);
+        assert_eq!(BestFit.pick_partner(&user(), &[]), None);
+    }
+
+    #[test]
+    fn it_schedules_everyone_to_meet_everyone_once() {
+        let ids = [USER1_ID, USER2_ID, USER3_ID, USER4_ID, USER5_ID];
+        let rounds = round_robin(&ids);
+        assert_eq!(rounds.len(), 5);
+
+        let mut pairs = HashSet::new();
+        let mut byes = HashSet::new();
+        for round in &rounds {
+            assert_eq!(round.pairs.len(), 2);
+            byes.insert(round.bye.unwrap());
+            for (a, b) in &round.pairs {
+                assert!(pairs.insert((*a.min(b), *a.max(b))));
+            }
+        }
+        // 5 users make 10 distinct pairs, and everyone sits out once
+        assert_eq!(pairs.len(), 10);
+        assert_eq!(byes.len(), 5);
+
+        assert_eq!(
+            round_robin(&[USER1_ID, USER2_ID, USER3_ID, USER4_ID]).len(),
+            3
+        );
+        assert!(round_robin(&[]).is_empty());
+    }
+
+    #[test]
+    fn it_groups_users_into_pods_with_few_repeats() {
+        let ids = [USER1_ID, USER2_ID, USER3_ID, USER4_ID, USER5_ID];
+        let previous_matches = vec![vec![USER1_ID, USER2_ID, USER3_ID]];
+
+        let pods = group_into_pods(&ids, 3, &previous_matches);
+        assert_eq!(pods.len(), 2);
+        assert_eq!(pods[1].len(), 2);
+        // user 1 already met users 2 and 3, so their pod is filled up with users 4 and 5
+        assert_eq!(pods[0], vec![USER1_ID, USER4_ID, USER5_ID]);
+        assert!(have_met(&previous_matches, &USER3_ID, &USER2_ID));
+
+        assert!(group_into_pods(&[USER1_ID], 3, &[]).is_empty());
+    }
+
+    #[test]
+    fn it_pairs_up_as_many_users_as_possible() {
+        let (a, b, c, d) = (USER1_ID, USER2_ID, USER3_ID, USER4_ID);
+        let previous_matches = vec![vec![a, d], vec![c, d], vec![b, c]];
+        let can_pair = |x: &Uuid, y: &Uuid| !have_met(&previous_matches, x, y);
+
+        // pairing a with b first leaves c and d, who met before
+        let pairs = maximum_pairing(&[a, b, c, d], &[(a, b)], can_pair);
+        assert_eq!(pairs, vec![(a, c), (b, d)]);
+        assert_eq!(next_round_pairs(&[a, b, c, d], &previous_matches).len(), 2);
+    }
+
+    #[test]
+    fn it_finds_maximum_pairings_through_odd_cycles() {
+        /// The most pairs of `edges` without a vertex in two of them.
+        fn brute_force(edges: &[(usize, usize)], used: &mut [bool]) -> usize {
+            let mut best = 0;
+            for (i, (a, b)) in edges.iter().enumerate() {
+                if !used[*a] && !used[*b] {
+                    used[*a] = true;
+                    used[*b] = true;
+                    best = best.max(1 + brute_force(&edges[i + 1..], used));
+                    used[*a] = false;
+                    used[*b] = false;
+                }
+            }
+            best
+        }
+
+        let rng = fastrand::Rng::with_seed(7);
+        for _ in 0..200 {
+            let ids: Vec<Uuid> = (0..8).map(|_| Uuid::new_v4()).collect();
+            let edges: Vec<(usize, usize)> = (0..8)
+                .flat_map(|a| (a + 1..8).map(move |b| (a, b)))
+                .filter(|_| rng.u8(..4) == 0)
+                .collect();
+            let can_pair = |x: &Uuid, y: &Uuid| {
+                let (x, y) = (
+                    ids.iter().position(|id| id == x).unwrap(),
+                    ids.iter().position(|id| id == y).unwrap(),
+                );
+                edges.contains(&(x.min(y), x.max(y)))
+            };
+
+            let pairs = maximum_pairing(&ids, &[], can_pair);
+            assert!(pairs.iter().all(|(x, y)| can_pair(x, y)));
+            assert_eq!(pairs.len(), brute_force(&edges, &mut [false; 8]));
+        }
+    }
+
+    #[test]
+    fn it_schedules_users_to_meet_everyone_on_the_other_side() {
+        let a = [USER1_ID, USER2_ID];
+        let b = [USER3_ID, USER4_ID, USER5_ID];
+        let mut previous_matches = Vec::new();
+
+        for _ in 0..3 {
+            let pairs = next_bipartite_round_pairs(&a, &b, &previous_matches);
+            assert_eq!(pairs.len(), 2);
+            for (x, y) in pairs {
+                assert!(b.contains(&x) && a.contains(&y));
+                previous_matches.push(vec![x, y]);
+            }
+        }
+        // everyone on side a met everyone on side b
+        assert!(next_bipartite_round_pairs(&a, &b, &previous_matches).is_empty());
+    }
+
+    #[test]
+    fn it_pairs_up_as_many_users_across_sides_as_possible() {
+        let a = [USER1_ID, USER2_ID, USER3_ID];
+        let b = [USER4_ID, USER5_ID, USER6_ID];
+        // only user 1 and 6, 2 and 5, and 2 and 6 haven't met
+        let previous_matches = vec![
+            vec![USER1_ID, USER4_ID],
+            vec![USER1_ID, USER5_ID],
+            vec![USER2_ID, USER4_ID],
+            vec![USER3_ID, USER4_ID],
+            vec![USER3_ID, USER5_ID],
+            vec![USER3_ID, USER6_ID],
+        ];
+
+        let mut pairs = next_bipartite_round_pairs(&a, &b, &previous_matches);
+        pairs.sort();
+        assert_eq!(pairs, vec![(USER1_ID, USER6_ID), (USER2_ID, USER5_ID)]);
+    }
+
+    #[test]
+    fn it_picks_the_partner_whose_profile_fits_best() {
+        let mut user = user();
+        user.profile = Profile::new(
+            Some("Remote"),
+            Some("music, hiking"),
+            Some("EN"),
+            Some(MediaPreference::Video),
+        );
+        let mut candidates = candidates();
+        assert_eq!(BestFit.pick_partner(&user, &candidates), Some(USER3_ID));
+
+        candidates[0].profile = Profile::new(None, Some("music"), Some("de"), None);
+        candidates[1].profile = Profile::new(Some("remote"), Some("hiking"), Some("en"), None);
+        assert_eq!(BestFit.pick_partner(&user, &candidates), Some(USER2_ID));
+
+        candidates[1].profile.media = Some(MediaPreference::AudioOnly);
+        candidates[1].profile.language = Some("fr".to_string());
+        assert_eq!(BestFit.pick_partner(&user, &candidates), Some(USER3_ID));
+    }
+
+    #[test]
+    fn it_limits_the_size_of_profiles() {
+        let interests: Vec<String> = (0..30).map(|i| format!("interest {}", i)).collect();
+        let long_tag = "a".repeat(51);
+        let profile = Profile::new(
+            Some(&format!("Remote, remote, {}, office", long_tag)),
+            Some(&interests.join(",")),
+            Some(&long_tag),
+            None,
+        );
+
+        assert_eq!(profile.tags, vec!["office", "remote"]);
+        assert_eq!(profile.interests.len(), 20);
+        assert!(profile.interests.contains(&"interest 19".to_string()));
+        assert!(!profile.interests.contains(&"interest 20".to_string()));
+        assert_eq!(profile.language, None);
+    }
+}
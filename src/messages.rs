@@ -1,38 +1,77 @@
 use actix::Recipient;
 use actix_derive::Message;
+use actix_web_actors::ws::CloseReason;
+use std::time::Duration;
 use uuid::Uuid;
 
+use crate::handshake::ClientProtocol;
+use crate::matching::Profile;
+use crate::protocol::{Severity, Side};
+use crate::room::{Announcement, RoomSettingsUpdate, RoomState};
+
 pub enum ClientKind {
     Admin,
-    User(String),
+    User {
+        name: String,
+        /// Token from an earlier session's `self-joined` event to reattach to that session.
+        resume_token: Option<String>,
+        side: Option<Side>,
+        profile: Profile,
+    },
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct WebSocketMessage(pub String);
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Close(Option<CloseReason>),
+}
 
+/// Responds with the id the client has in the room, which is the one of the earlier session if a
+/// user resumed one and `id` otherwise.
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Uuid")]
 pub struct ClientConnect {
     pub addr: Recipient<WebSocketMessage>,
     pub kind: ClientKind,
     pub id: Uuid,
+    pub protocol: ClientProtocol,
 }
 
 impl ClientConnect {
-    pub fn user(addr: Recipient<WebSocketMessage>, id: Uuid, name: String) -> ClientConnect {
+    pub fn user(
+        addr: Recipient<WebSocketMessage>,
+        id: Uuid,
+        protocol: ClientProtocol,
+        name: String,
+        resume_token: Option<String>,
+        side: Option<Side>,
+        profile: Profile,
+    ) -> ClientConnect {
         ClientConnect {
             id,
             addr,
-            kind: ClientKind::User(name),
+            kind: ClientKind::User {
+                name,
+                resume_token,
+                side,
+                profile,
+            },
+            protocol,
         }
     }
 
-    pub fn admin(addr: Recipient<WebSocketMessage>, id: Uuid) -> ClientConnect {
+    pub fn admin(
+        addr: Recipient<WebSocketMessage>,
+        id: Uuid,
+        protocol: ClientProtocol,
+    ) -> ClientConnect {
         ClientConnect {
             id,
             addr,
             kind: ClientKind::Admin,
+            protocol,
         }
     }
 }
@@ -41,11 +80,54 @@ impl ClientConnect {
 #[rtype(result = "()")]
 pub struct ClientDisconnect {
     pub id: Uuid,
+    /// The `id` the connection was opened with; differs from `id` for resumed sessions.
+    pub connection_id: Uuid,
+    /// Whether the connection was closed on purpose, by the client or by the server because the
+    /// client misbehaved, rather than dropping. There's no point in waiting for the user to resume
+    /// their session then.
+    pub closed_deliberately: bool,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ClientMessage {
     pub id: Uuid,
-    pub payload: String,
+    /// The message as received, in the encoding the connection negotiated.
+    pub payload: Vec<u8>,
+}
+
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct VerifyAdminToken {
+    pub token: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "RoomState")]
+pub struct GetRoomState;
+
+#[derive(Message)]
+#[rtype(result = "RoomState")]
+pub struct UpdateRoom {
+    pub name: Option<String>,
+    pub settings: Option<RoomSettingsUpdate>,
+}
+
+/// An announcement made via the REST API.
+#[derive(Message)]
+#[rtype(result = "Result<Announcement, String>")]
+pub struct Announce {
+    pub message: String,
+    pub severity: Severity,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseRoom;
+
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct IsRoomExpired {
+    pub idle_ttl: Duration,
 }
@@ -0,0 +1,106 @@
+//! Process-wide counters, exposed in the Prometheus text format via `GET /metrics`.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Why a WebSocket connection was closed because of a protocol error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolErrorKind {
+    /// A frame or message exceeded the size limit.
+    Oversized,
+    /// A text message wasn't valid UTF-8.
+    InvalidUtf8,
+    /// The client violated the WebSocket framing rules, e.g. with an unmasked frame.
+    Malformed,
+    /// Reading from the socket failed.
+    Io,
+}
+
+impl ProtocolErrorKind {
+    const ALL: [ProtocolErrorKind; 4] = [
+        ProtocolErrorKind::Oversized,
+        ProtocolErrorKind::InvalidUtf8,
+        ProtocolErrorKind::Malformed,
+        ProtocolErrorKind::Io,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            ProtocolErrorKind::Oversized => "oversized",
+            ProtocolErrorKind::InvalidUtf8 => "invalid_utf8",
+            ProtocolErrorKind::Malformed => "malformed",
+            ProtocolErrorKind::Io => "io",
+        }
+    }
+}
+
+static PROTOCOL_ERRORS: [AtomicU64; ProtocolErrorKind::ALL.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+static RATE_LIMITED_MESSAGES: AtomicU64 = AtomicU64::new(0);
+static RATE_LIMIT_DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_protocol_error(kind: ProtocolErrorKind) {
+    PROTOCOL_ERRORS[kind as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_rate_limited_message() {
+    RATE_LIMITED_MESSAGES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_rate_limit_disconnect() {
+    RATE_LIMIT_DISCONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn render() -> String {
+    let mut output = String::new();
+
+    output.push_str("# HELP websocket_protocol_errors_total WebSocket connections closed because of a protocol error.\n");
+    output.push_str("# TYPE websocket_protocol_errors_total counter\n");
+    for kind in ProtocolErrorKind::ALL {
+        let _ = writeln!(
+            output,
+            "websocket_protocol_errors_total{{kind=\"{}\"}} {}",
+            kind.label(),
+            PROTOCOL_ERRORS[kind as usize].load(Ordering::Relaxed)
+        );
+    }
+
+    output.push_str(
+        "# HELP rate_limited_messages_total Messages dropped because a rate limit was hit.\n",
+    );
+    output.push_str("# TYPE rate_limited_messages_total counter\n");
+    let _ = writeln!(
+        output,
+        "rate_limited_messages_total {}",
+        RATE_LIMITED_MESSAGES.load(Ordering::Relaxed)
+    );
+
+    output.push_str("# HELP rate_limit_disconnects_total Connections closed for repeatedly hitting rate limits.\n");
+    output.push_str("# TYPE rate_limit_disconnects_total counter\n");
+    let _ = writeln!(
+        output,
+        "rate_limit_disconnects_total {}",
+        RATE_LIMIT_DISCONNECTS.load(Ordering::Relaxed)
+    );
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_protocol_error, render, ProtocolErrorKind};
+
+    #[test]
+    fn it_counts_protocol_errors_by_kind() {
+        record_protocol_error(ProtocolErrorKind::InvalidUtf8);
+
+        let output = render();
+        assert!(output.contains("websocket_protocol_errors_total{kind=\"oversized\"}"));
+        assert!(!output.contains("websocket_protocol_errors_total{kind=\"invalid_utf8\"} 0"));
+    }
+}
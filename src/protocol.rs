@@ -0,0 +1,482 @@
+//! The messages exchanged with clients over the WebSocket connection. Clients send
+//! [`ClientCommand`]s and the server sends [`ServerEvent`]s; both are encoded as objects with the
+//! variant name in `type` and its fields in `data`, as JSON or MessagePack depending on the
+//! subprotocol the connection negotiated.
+//!
+//! This module only depends on `serde` and `uuid` so it can be shared with Rust clients.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+/// Messages clients send to the server. None of them carry the sender's own id as the server
+/// always acts on behalf of the connection a command was received on.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ClientCommand {
+    #[serde(rename = "ready-to-match")]
+    ReadyToMatch,
+    #[serde(rename = "skip-match")]
+    SkipMatch,
+    /// `id` is the match partner the candidate is sent to.
+    #[serde(rename = "ice-candidate")]
+    ICECandidate { id: Uuid, description: String },
+    /// `id` is the match partner the offer is sent to.
+    #[serde(rename = "rtc-connection-offer")]
+    RTCConnectionOffer { id: Uuid, description: String },
+    /// `id` is the match partner the answer is sent to.
+    #[serde(rename = "rtc-connection-answer")]
+    RTCConnectionAnswer { id: Uuid, description: String },
+    /// A text message for the current match partner, e.g. for when the media connection fails.
+    #[serde(rename = "chat-message")]
+    ChatMessage { text: String },
+}
+
+/// Messages admins send over the admin connection. Each one is answered with a
+/// `command-acknowledged` or an `error` event, and reported to the other admins.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum AdminCommand {
+    /// Removes the user from the room and closes their connection, giving `reason`.
+    #[serde(rename = "kick-user")]
+    KickUser { id: Uuid, reason: Option<String> },
+    /// Ends the match the user `id` is in.
+    #[serde(rename = "end-match")]
+    EndMatch { id: Uuid },
+    #[serde(rename = "end-all-matches")]
+    EndAllMatches,
+    /// Matches the two users with each other, regardless of whether they were ready or met before.
+    #[serde(rename = "pair-users")]
+    PairUsers { users: (Uuid, Uuid) },
+    /// Stops matching queued users; they keep their place until matching is resumed.
+    #[serde(rename = "pause-matching")]
+    PauseMatching,
+    #[serde(rename = "resume-matching")]
+    ResumeMatching,
+    /// Ends all matches and pairs up everyone present at once, then does so again every time the
+    /// match duration is up until everyone met everyone or rounds are stopped.
+    #[serde(rename = "start-round")]
+    StartRound,
+    /// Stops starting new rounds; the current round's matches run until their time is up.
+    #[serde(rename = "stop-rounds")]
+    StopRounds,
+    /// Shows `message` to every user in the room, and to users joining before `expires_at` (a
+    /// Unix timestamp) if given.
+    #[serde(rename = "announce")]
+    Announce {
+        message: String,
+        #[serde(default)]
+        severity: Severity,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    /// Starts or stops sending the admin a `chat-message-copy` of every chat message in the room.
+    #[serde(rename = "moderate-chat")]
+    ModerateChat { enabled: bool },
+}
+
+/// Messages the server sends to users and admins.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum ServerEvent {
+    /// `resume_token` lets the user reattach to their session after their connection dropped.
+    /// `version` and `capabilities` are what was negotiated when the connection was opened.
+    #[serde(rename = "self-joined")]
+    SelfJoined {
+        id: Uuid,
+        /// Only sent to clients with the `resume` capability.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    /// `side` is the group the user joined as in rooms that only match users across groups.
+    #[serde(rename = "user-joined")]
+    UserJoined {
+        id: Uuid,
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        side: Option<Side>,
+    },
+    #[serde(rename = "user-present")]
+    UserPresent {
+        id: Uuid,
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        side: Option<Side>,
+    },
+    /// `role` is only sent to clients with the `match-roles` capability.
+    #[serde(rename = "user-matched")]
+    UserMatched {
+        id: Uuid,
+        name: String,
+        duration: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        role: Option<MatchRole>,
+    },
+    #[serde(rename = "queued")]
+    Queued { position: usize },
+    #[serde(rename = "queue-position")]
+    QueuePosition { position: usize },
+    #[serde(rename = "match-ended")]
+    MatchEnded { id: Uuid },
+    #[serde(rename = "user-left")]
+    UserLeft { id: Uuid },
+    /// The match partner's connection dropped; they might resume their session shortly.
+    #[serde(rename = "user-reconnecting")]
+    UserReconnecting { id: Uuid },
+    #[serde(rename = "user-reconnected")]
+    UserReconnected { id: Uuid },
+    /// `id` is the match partner the candidate was sent by.
+    #[serde(rename = "ice-candidate")]
+    ICECandidate { id: Uuid, description: String },
+    /// `id` is the match partner the offer was sent by.
+    #[serde(rename = "rtc-connection-offer")]
+    RTCConnectionOffer { id: Uuid, description: String },
+    /// `id` is the match partner the answer was sent by.
+    #[serde(rename = "rtc-connection-answer")]
+    RTCConnectionAnswer { id: Uuid, description: String },
+    /// `id` is the match partner the message was sent by, `sent_at` the Unix timestamp in
+    /// milliseconds at which the server received it.
+    #[serde(rename = "chat-message")]
+    ChatMessage {
+        id: Uuid,
+        text: String,
+        sent_at: u64,
+    },
+    /// Sent to admins who opted in via `moderate-chat`. `text` is what the sender wrote, before
+    /// blocked words were masked; `censored` tells whether any were.
+    #[serde(rename = "chat-message-copy")]
+    ChatMessageCopy {
+        id: Uuid,
+        recipient_ids: Vec<Uuid>,
+        text: String,
+        sent_at: u64,
+        censored: bool,
+    },
+    /// Sent to admins when a round was started; `byes` are the users sitting it out.
+    #[serde(rename = "round-started")]
+    RoundStarted {
+        round: u32,
+        duration: u64,
+        byes: Vec<Uuid>,
+    },
+    /// Sent to users who have no partner in the round that just started.
+    #[serde(rename = "round-bye")]
+    RoundBye { round: u32 },
+    /// Sent to admins when rounds stopped because everyone present met everyone else.
+    #[serde(rename = "rounds-finished")]
+    RoundsFinished { rounds: u32 },
+    /// Sent to admins of rooms that only match users across groups when users join or leave.
+    #[serde(rename = "group-balance-changed")]
+    GroupBalanceChanged { balance: GroupBalance },
+    /// Each match is the list of users in it: two for pairs, more for pods.
+    #[serde(rename = "active-matches-changed")]
+    ActiveMatchesChanged { matches: Vec<Vec<Uuid>> },
+    /// `id` identifies the announcement so clients can tell whether they've already shown it.
+    #[serde(rename = "announcement")]
+    Announcement {
+        id: Uuid,
+        message: String,
+        severity: Severity,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    /// Sent to the admin whose command was carried out.
+    #[serde(rename = "command-acknowledged")]
+    CommandAcknowledged {
+        command: AdminCommand,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        correlation_id: Option<String>,
+    },
+    /// Sent to all other admins when `id` carried out a command.
+    #[serde(rename = "admin-command-executed")]
+    AdminCommandExecuted { id: Uuid, command: AdminCommand },
+    #[serde(rename = "room-closed")]
+    RoomClosed,
+    #[serde(rename = "error")]
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// The `correlation_id` of the client message that caused the error, if it had one.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        correlation_id: Option<String>,
+    },
+}
+
+/// Machine-readable reasons for rejecting a client's message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message isn't valid JSON.
+    InvalidJson,
+    /// The message isn't valid MessagePack.
+    InvalidMessagePack,
+    /// The message decodes but isn't a known command or is missing data.
+    InvalidEvent,
+    /// The event is only ever sent by the server.
+    UnexpectedEvent,
+    /// The user is not matched with the user they are trying to reach, or not matched at all.
+    NotMatched,
+    /// The user is already in a match.
+    AlreadyMatched,
+    /// There's no user with the given id in the room.
+    UnknownRecipient,
+    /// The room only matches users across groups and the user didn't join as either side.
+    SideRequired,
+    /// The chat message is longer than the server allows.
+    MessageTooLong,
+    /// None of the user's partners negotiated the `chat` capability.
+    ChatUnsupported,
+    /// The client sent too many messages, or too many of this type, and the message was dropped.
+    RateLimited,
+}
+
+/// The group a user belongs to in rooms that only match users of one group with users of the
+/// other, e.g. mentors and mentees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    A,
+    B,
+}
+
+/// How many users of a room are on each side.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupBalance {
+    pub a: usize,
+    pub b: usize,
+    /// Users who didn't join as either side.
+    pub unassigned: usize,
+}
+
+/// How prominently clients should show an announcement.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+/// Which side of the WebRTC connection a matched user sets up. The server assigns the roles so
+/// both partners never send offers at the same time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchRole {
+    Offerer,
+    Answerer,
+}
+
+impl fmt::Debug for ClientCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientCommand::ReadyToMatch => write!(f, "ReadyToMatch"),
+            ClientCommand::SkipMatch => write!(f, "SkipMatch"),
+            ClientCommand::ICECandidate { id, .. } => {
+                write!(f, r#"ICECandidate ( id: {:?}, description: "..." )"#, id)
+            }
+            ClientCommand::RTCConnectionOffer { id, .. } => write!(
+                f,
+                r#"RTCConnectionOffer ( id: {:?}, description: "..." )"#,
+                id
+            ),
+            ClientCommand::RTCConnectionAnswer { id, .. } => write!(
+                f,
+                r#"RTCConnectionAnswer ( id: {:?}, description: "..." )"#,
+                id
+            ),
+            ClientCommand::ChatMessage { .. } => write!(f, r#"ChatMessage ( text: "..." )"#),
+        }
+    }
+}
+
+impl ServerEvent {
+    /// The capability a client must have asked for to be sent this event, if any.
+    pub fn required_capability(&self) -> Option<&'static str> {
+        match self {
+            ServerEvent::Error { .. } => Some("errors"),
+            ServerEvent::QueuePosition { .. } => Some("queue-position"),
+            ServerEvent::ChatMessage { .. } => Some("chat"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for ServerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerEvent::SelfJoined {
+                id,
+                version,
+                capabilities,
+                ..
+            } => write!(
+                f,
+                r#"SelfJoined ( id: {:?}, resume_token: "...", version: {:?}, capabilities: {:?} )"#,
+                id, version, capabilities
+            ),
+            ServerEvent::UserJoined { id, name, side } => write!(
+                f,
+                "UserJoined ( id: {:?}, name: {:?}, side: {:?} )",
+                id, name, side
+            ),
+            ServerEvent::UserPresent { id, name, side } => write!(
+                f,
+                "UserPresent ( id: {:?}, name: {:?}, side: {:?} )",
+                id, name, side
+            ),
+            ServerEvent::UserMatched {
+                id,
+                name,
+                duration,
+                role,
+            } => {
+                write!(
+                    f,
+                    "UserMatched ( id: {:?}, name: {:?}, duration: {:?}, role: {:?} )",
+                    id, name, duration, role
+                )
+            }
+            ServerEvent::Queued { position } => write!(f, "Queued ( position: {:?} )", position),
+            ServerEvent::QueuePosition { position } => {
+                write!(f, "QueuePosition ( position: {:?} )", position)
+            }
+            ServerEvent::MatchEnded { id } => {
+                write!(f, "MatchEnded ( id: {:?} )", id)
+            }
+            ServerEvent::UserLeft { id } => write!(f, "UserLeft ( id: {:?} )", id),
+            ServerEvent::UserReconnecting { id } => {
+                write!(f, "UserReconnecting ( id: {:?} )", id)
+            }
+            ServerEvent::UserReconnected { id } => write!(f, "UserReconnected ( id: {:?} )", id),
+            ServerEvent::ICECandidate { id, .. } => {
+                write!(f, r#"ICECandidate ( id: {:?}, description: "..." )"#, id)
+            }
+            ServerEvent::RTCConnectionOffer { id, .. } => write!(
+                f,
+                r#"RTCConnectionOffer ( id: {:?}, description: "..." )"#,
+                id
+            ),
+            ServerEvent::RTCConnectionAnswer { id, .. } => write!(
+                f,
+                r#"RTCConnectionAnswer ( id: {:?}, description: "..." )"#,
+                id
+            ),
+            ServerEvent::ChatMessage { id, sent_at, .. } => write!(
+                f,
+                r#"ChatMessage ( id: {:?}, text: "...", sent_at: {:?} )"#,
+                id, sent_at
+            ),
+            ServerEvent::ChatMessageCopy {
+                id,
+                recipient_ids,
+                sent_at,
+                censored,
+                ..
+            } => write!(
+                f,
+                r#"ChatMessageCopy ( id: {:?}, recipient_ids: {:?}, text: "...", sent_at: {:?}, censored: {:?} )"#,
+                id, recipient_ids, sent_at, censored
+            ),
+            ServerEvent::RoundStarted {
+                round,
+                duration,
+                byes,
+            } => write!(
+                f,
+                "RoundStarted ( round: {:?}, duration: {:?}, byes: {:?} )",
+                round, duration, byes
+            ),
+            ServerEvent::RoundBye { round } => write!(f, "RoundBye ( round: {:?} )", round),
+            ServerEvent::RoundsFinished { rounds } => {
+                write!(f, "RoundsFinished ( rounds: {:?} )", rounds)
+            }
+            ServerEvent::GroupBalanceChanged { balance } => {
+                write!(f, "GroupBalanceChanged ( balance: {:?} )", balance)
+            }
+            ServerEvent::ActiveMatchesChanged { matches } => {
+                write!(f, "ActiveMatchesChanged ( matches: {:?} )", matches)
+            }
+            ServerEvent::Announcement {
+                id,
+                message,
+                severity,
+                expires_at,
+            } => write!(
+                f,
+                "Announcement ( id: {:?}, message: {:?}, severity: {:?}, expires_at: {:?} )",
+                id, message, severity, expires_at
+            ),
+            ServerEvent::CommandAcknowledged {
+                command,
+                correlation_id,
+            } => write!(
+                f,
+                "CommandAcknowledged ( command: {:?}, correlation_id: {:?} )",
+                command, correlation_id
+            ),
+            ServerEvent::AdminCommandExecuted { id, command } => write!(
+                f,
+                "AdminCommandExecuted ( id: {:?}, command: {:?} )",
+                id, command
+            ),
+            ServerEvent::RoomClosed => write!(f, "RoomClosed"),
+            ServerEvent::Error {
+                code,
+                message,
+                correlation_id,
+            } => write!(
+                f,
+                "Error ( code: {:?}, message: {:?}, correlation_id: {:?} )",
+                code, message, correlation_id
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientCommand, ServerEvent};
+    use serde_json::json;
+    use uuid::{uuid, Uuid};
+
+    const USER_ID: Uuid = uuid!("11111111-06c9-4f14-bf8b-fafce92d6396");
+
+    #[test]
+    fn it_parses_client_commands() {
+        let command: ClientCommand = serde_json::from_value(json!({
+            "type": "ice-candidate",
+            "data": { "id": USER_ID, "description": "candidate" }
+        }))
+        .unwrap();
+
+        assert!(matches!(
+            command,
+            ClientCommand::ICECandidate { id: USER_ID, description } if description == "candidate"
+        ));
+    }
+
+    #[test]
+    fn it_rejects_server_events_sent_as_commands() {
+        let command = serde_json::from_value::<ClientCommand>(json!({
+            "type": "user-left",
+            "data": { "id": USER_ID }
+        }));
+
+        assert!(command.is_err());
+    }
+
+    #[test]
+    fn it_serializes_server_events() {
+        assert_eq!(
+            serde_json::to_value(ServerEvent::MatchEnded { id: USER_ID }).unwrap(),
+            json!({ "type": "match-ended", "data": { "id": USER_ID } })
+        );
+        assert_eq!(
+            serde_json::to_value(ServerEvent::RoomClosed).unwrap(),
+            json!({ "type": "room-closed" })
+        );
+    }
+}
@@ -0,0 +1,281 @@
+//! Token bucket rate limits on the messages a connection sends, so a single client can't flood
+//! the room.
+
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// How long rejected messages count towards closing a connection.
+const VIOLATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Allows `per_second` messages on average with bursts of up to `burst` messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl RateLimit {
+    pub fn new(per_second: f64, burst: f64) -> RateLimit {
+        RateLimit { per_second, burst }
+    }
+}
+
+/// Parses `<per second>/<burst>`, e.g. `20/40`. The burst must allow at least one message.
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<RateLimit, String> {
+        let (per_second, burst) = value
+            .split_once('/')
+            .ok_or_else(|| format!("expected <per second>/<burst> but got {:?}", value))?;
+        let parse = |number: &str| {
+            number
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|number| *number > 0.0)
+                .ok_or_else(|| format!("{:?} is not a positive number", number))
+        };
+
+        let (per_second, burst) = (parse(per_second)?, parse(burst)?);
+        if burst < 1.0 {
+            return Err(format!("burst {} must be at least 1", burst));
+        }
+
+        Ok(RateLimit::new(per_second, burst))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    /// Applies to all messages of a connection.
+    pub connection: RateLimit,
+    /// Applies to messages of a type, keyed by their `type`, on top of the connection's limit.
+    pub message_types: HashMap<String, RateLimit>,
+    /// How many messages of a connection may be rejected within a minute before it is closed.
+    pub max_violations: u32,
+}
+
+impl RateLimits {
+    /// Parses `<type>=<per second>/<burst>` pairs separated by commas, e.g.
+    /// `ready-to-match=0.5/3,ice-candidate=50/100`.
+    pub fn parse_message_types(value: &str) -> Result<HashMap<String, RateLimit>, String> {
+        value
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (message_type, limit) = pair
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected <type>=<limit> but got {:?}", pair))?;
+                Ok((message_type.trim().to_string(), limit.parse()?))
+            })
+            .collect()
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            connection: RateLimit::new(20.0, 40.0),
+            message_types: HashMap::from([
+                ("ready-to-match".to_string(), RateLimit::new(0.5, 3.0)),
+                ("skip-match".to_string(), RateLimit::new(0.5, 3.0)),
+                ("ice-candidate".to_string(), RateLimit::new(20.0, 50.0)),
+                ("rtc-connection-offer".to_string(), RateLimit::new(1.0, 5.0)),
+                (
+                    "rtc-connection-answer".to_string(),
+                    RateLimit::new(1.0, 5.0),
+                ),
+                ("chat-message".to_string(), RateLimit::new(2.0, 10.0)),
+            ]),
+            max_violations: 10,
+        }
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.refilled_at = now;
+    }
+}
+
+/// The rate limit state of a single connection.
+pub struct RateLimiter {
+    limits: RateLimits,
+    connection: TokenBucket,
+    message_types: HashMap<String, TokenBucket>,
+    /// When messages were rejected within the last `VIOLATION_WINDOW`.
+    violations: VecDeque<Instant>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// The message must be dropped.
+    Limited,
+    /// The message must be dropped and the connection closed.
+    Exceeded,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            connection: TokenBucket::new(limits.connection, Instant::now()),
+            message_types: HashMap::new(),
+            violations: VecDeque::new(),
+            limits,
+        }
+    }
+
+    /// Takes a token for a message of the given type if both the connection's and the type's
+    /// bucket have one left.
+    pub fn check(&mut self, message_type: Option<&str>) -> Verdict {
+        self.check_at(message_type, Instant::now())
+    }
+
+    fn check_at(&mut self, message_type: Option<&str>, now: Instant) -> Verdict {
+        self.connection.refill(now);
+        let message_type_bucket = match message_type
+            .and_then(|message_type| self.limits.message_types.get_key_value(message_type))
+        {
+            Some((message_type, limit)) => {
+                let bucket = self
+                    .message_types
+                    .entry(message_type.clone())
+                    .or_insert_with(|| TokenBucket::new(*limit, now));
+                bucket.refill(now);
+                Some(bucket)
+            }
+            None => None,
+        };
+
+        let has_tokens = self.connection.tokens >= 1.0
+            && message_type_bucket
+                .as_ref()
+                .is_none_or(|bucket| bucket.tokens >= 1.0);
+        if has_tokens {
+            self.connection.tokens -= 1.0;
+            if let Some(bucket) = message_type_bucket {
+                bucket.tokens -= 1.0;
+            }
+            return Verdict::Allowed;
+        }
+
+        while self.violations.front().is_some_and(|violated_at| {
+            now.saturating_duration_since(*violated_at) >= VIOLATION_WINDOW
+        }) {
+            self.violations.pop_front();
+        }
+        self.violations.push_back(now);
+        if self.violations.len() > self.limits.max_violations as usize {
+            Verdict::Exceeded
+        } else {
+            Verdict::Limited
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimit, RateLimiter, RateLimits, Verdict};
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            connection: RateLimit::new(10.0, 5.0),
+            message_types: HashMap::from([(
+                "ready-to-match".to_string(),
+                RateLimit::new(1.0, 1.0),
+            )]),
+            max_violations: 2,
+        })
+    }
+
+    #[test]
+    fn it_limits_message_types_separately() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.check_at(Some("ready-to-match"), now),
+            Verdict::Allowed
+        );
+        assert_eq!(
+            limiter.check_at(Some("ready-to-match"), now),
+            Verdict::Limited
+        );
+        // other messages still have tokens left
+        assert_eq!(
+            limiter.check_at(Some("ice-candidate"), now),
+            Verdict::Allowed
+        );
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            limiter.check_at(Some("ready-to-match"), later),
+            Verdict::Allowed
+        );
+    }
+
+    #[test]
+    fn it_reports_repeat_offenders() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert_eq!(limiter.check_at(None, now), Verdict::Allowed);
+        }
+        assert_eq!(limiter.check_at(None, now), Verdict::Limited);
+        assert_eq!(limiter.check_at(None, now), Verdict::Limited);
+        assert_eq!(limiter.check_at(None, now), Verdict::Exceeded);
+    }
+
+    #[test]
+    fn it_forgets_violations_after_a_while() {
+        let mut limiter = limiter();
+        let mut now = Instant::now();
+
+        // a client that's too quick now and then is never closed
+        for _ in 0..10 {
+            for _ in 0..5 {
+                assert_eq!(limiter.check_at(None, now), Verdict::Allowed);
+            }
+            assert_eq!(limiter.check_at(None, now), Verdict::Limited);
+            now += Duration::from_secs(30);
+        }
+    }
+
+    #[test]
+    fn it_parses_rate_limits() {
+        assert_eq!(
+            RateLimits::parse_message_types("ready-to-match=0.5/3, ice-candidate=50/100").unwrap(),
+            HashMap::from([
+                ("ready-to-match".to_string(), RateLimit::new(0.5, 3.0)),
+                ("ice-candidate".to_string(), RateLimit::new(50.0, 100.0)),
+            ])
+        );
+        assert!("0/1".parse::<RateLimit>().is_err());
+        assert!("1/0.5".parse::<RateLimit>().is_err());
+        assert!("5".parse::<RateLimit>().is_err());
+    }
+}
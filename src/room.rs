@@ -1,91 +1,164 @@
-use actix::prelude::{Actor, AsyncContext, Context, Handler, Recipient};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fmt;
+use actix::prelude::{
+    Actor, ActorContext, AsyncContext, Context, Handler, MessageResult, Recipient, SpawnHandle,
+};
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::iter::repeat_with;
+use std::ops::RangeInclusive;
 use std::string::String;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+use crate::config::Config;
+use crate::handshake::ClientProtocol;
+use crate::matching::{
+    group_into_pods, have_met, next_bipartite_round_pairs, next_round_pairs, Candidate,
+    MatchStrategy, MatchStrategyKind, Profile,
+};
 use crate::messages::{
-    ClientConnect, ClientDisconnect, ClientKind, ClientMessage, WebSocketMessage,
+    Announce, ClientConnect, ClientDisconnect, ClientKind, ClientMessage, CloseRoom, GetRoomState,
+    IsRoomExpired, UpdateRoom, VerifyAdminToken, WebSocketMessage,
+};
+use crate::protocol::{
+    AdminCommand, ClientCommand, ErrorCode, GroupBalance, MatchRole, ServerEvent, Severity, Side,
+};
+use crate::util::{
+    constant_time_eq, is_dev_mode, truncate_to_char_boundary, unix_timestamp, unix_timestamp_millis,
 };
-use crate::util::is_dev_mode;
 
 const MATCH_DURATION: Duration = Duration::from_secs(120);
 const MATCH_DURATION_DEV_MODE: Duration = Duration::from_secs(15);
+/// The most users a match may have; every user in it connects to every other one.
+pub const MAX_POD_SIZE: usize = 8;
+/// How long a ready user waits for a full pod before they are matched with fewer users.
+const POD_FILL_TIMEOUT: Duration = Duration::from_secs(30);
+/// The most bytes the reason of a close frame may have.
+const MAX_CLOSE_REASON_LENGTH: usize = 123;
+
+/// Why a client's message was rejected; sent back to the client as an `error` event.
+#[derive(Debug)]
+struct ClientError {
+    code: ErrorCode,
+    message: String,
+}
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "type", content = "data")]
-enum Event {
-    #[serde(rename = "self-joined")]
-    SelfJoined { id: Uuid },
-    #[serde(rename = "user-joined")]
-    UserJoined { id: Uuid, name: String },
-    #[serde(rename = "user-present")]
-    UserPresent { id: Uuid, name: String },
-    #[serde(rename = "ready-to-match")]
-    ReadyToMatch { id: Uuid },
-    #[serde(rename = "user-matched")]
-    UserMatched {
-        id: Uuid,
-        name: String,
-        duration: u64,
-    },
-    #[serde(rename = "match-ended")]
-    MatchEnded { id: Uuid },
-    #[serde(rename = "user-left")]
-    UserLeft { id: Uuid },
-    #[serde(rename = "ice-candidate")]
-    ICECandidate { id: Uuid, description: String },
-    #[serde(rename = "rtc-connection-offer")]
-    RTCConnectionOffer { id: Uuid, description: String },
-    #[serde(rename = "rtc-connection-answer")]
-    RTCConnectionAnswer { id: Uuid, description: String },
-    #[serde(rename = "active-matches-changed")]
-    ActiveMatchesChanged { matches: Vec<(Uuid, Uuid)> },
-}
-
-impl fmt::Debug for Event {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &*self {
-            Event::SelfJoined { id } => write!(f, "SelfJoined ( id: {:?} )", id),
-            Event::UserJoined { id, name } => {
-                write!(f, "UserJoined ( id: {:?}, name: {:?} )", id, name)
-            }
-            Event::UserPresent { id, name } => {
-                write!(f, "UserPresent ( id: {:?}, name: {:?} )", id, name)
-            }
-            Event::ReadyToMatch { id } => {
-                write!(f, "ReadyToMatch ( id: {:?} )", id)
-            }
-            Event::UserMatched { id, name, duration } => {
-                write!(
-                    f,
-                    "UserMatched ( id: {:?}, name: {:?}, duration: {:?} )",
-                    id, name, duration
-                )
-            }
-            Event::MatchEnded { id } => {
-                write!(f, "MatchEnded ( id: {:?} )", id)
-            }
-            Event::UserLeft { id } => write!(f, "UserLeft ( id: {:?} )", id),
-            Event::ICECandidate { id, .. } => {
-                write!(f, r#"ICECandidate ( id: {:?}, description: "..." )"#, id)
-            }
-            Event::RTCConnectionOffer { id, .. } => write!(
-                f,
-                r#"RTCConnectionOffer ( id: {:?}, description: "..." )"#,
-                id
-            ),
-            Event::RTCConnectionAnswer { id, .. } => write!(
-                f,
-                r#"RTCConnectionAnswer ( id: {:?}, description: "..." )"#,
-                id
-            ),
-            Event::ActiveMatchesChanged { matches } => {
-                write!(f, "ActiveMatchesChanged ( matches: {:?} )", matches)
-            }
+impl ClientError {
+    fn new(code: ErrorCode, message: impl Into<String>) -> ClientError {
+        ClientError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl MatchRole {
+    /// The user with the lower id always makes the offer so the roles are the same no matter
+    /// who requested the match, including when the connection is renegotiated.
+    fn for_pair(id: &Uuid, partner_id: &Uuid) -> MatchRole {
+        if id < partner_id {
+            MatchRole::Offerer
+        } else {
+            MatchRole::Answerer
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RoomSettings {
+    /// How long each match lasts, in seconds.
+    pub match_duration: u64,
+    /// Words that are masked with asterisks in chat messages, regardless of case.
+    pub blocked_words: Vec<String>,
+    /// How many users are matched with each other, 2 for pairs.
+    pub pod_size: usize,
+    /// Only matches users of side a with users of side b, always in pairs.
+    pub bipartite: bool,
+}
+
+impl Default for RoomSettings {
+    fn default() -> RoomSettings {
+        let match_duration = if is_dev_mode() {
+            MATCH_DURATION_DEV_MODE
+        } else {
+            MATCH_DURATION
+        };
+
+        RoomSettings {
+            match_duration: match_duration.as_secs(),
+            blocked_words: Vec::new(),
+            pod_size: 2,
+            bipartite: false,
+        }
+    }
+}
+
+/// A partial update of a room's settings; fields that are `None` are left unchanged.
+#[derive(Debug, Default, Deserialize)]
+pub struct RoomSettingsUpdate {
+    pub match_duration: Option<u64>,
+    pub blocked_words: Option<Vec<String>>,
+    pub pod_size: Option<usize>,
+    pub bipartite: Option<bool>,
+}
+
+impl RoomSettings {
+    fn apply(&mut self, update: RoomSettingsUpdate) {
+        if let Some(match_duration) = update.match_duration {
+            self.match_duration = match_duration;
+        }
+        if let Some(blocked_words) = update.blocked_words {
+            self.blocked_words = blocked_words;
+        }
+        if let Some(pod_size) = update.pod_size {
+            self.pod_size = pod_size;
+        }
+        if let Some(bipartite) = update.bipartite {
+            self.bipartite = bipartite;
+        }
+    }
+}
+
+/// A snapshot of a room's state as exposed via the REST API.
+#[derive(Debug, Serialize)]
+pub struct RoomState {
+    pub id: Uuid,
+    pub name: String,
+    pub settings: RoomSettings,
+    pub expires_at: Option<u64>,
+    pub match_strategy: MatchStrategyKind,
+    pub users: usize,
+    pub admins: usize,
+    pub active_matches: usize,
+    pub previous_matches: usize,
+    pub matching_paused: bool,
+    /// The number of the current round while rounds are running.
+    pub round: Option<u32>,
+    pub group_balance: GroupBalance,
+}
+
+/// A message from the room's admins to all of its users.
+#[derive(Clone, Debug, Serialize)]
+pub struct Announcement {
+    pub id: Uuid,
+    pub message: String,
+    pub severity: Severity,
+    /// Unix timestamp until which users who join are shown the announcement too.
+    pub expires_at: Option<u64>,
+}
+
+impl Announcement {
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_none_or(|expires_at| unix_timestamp() >= expires_at)
+    }
+
+    fn event(&self) -> ServerEvent {
+        ServerEvent::Announcement {
+            id: self.id,
+            message: self.message.clone(),
+            severity: self.severity,
+            expires_at: self.expires_at,
         }
     }
 }
@@ -93,47 +166,119 @@ impl fmt::Debug for Event {
 struct UserConnectionInfo {
     name: String,
     socket_recipient: Recipient<WebSocketMessage>,
+    protocol: ClientProtocol,
+    /// Identifies the user's current connection so disconnects of replaced ones are ignored.
+    connection_id: Uuid,
+    resume_token: String,
+    /// Removes the user once the reconnect grace period is up; set while they are disconnected.
+    reconnect_timer: Option<SpawnHandle>,
+    joined_at: Instant,
+    /// When the user joined or their last match ended.
+    available_since: Instant,
+    /// When the user last sent `ready-to-match`; a timer's clock so tests can pause it.
+    ready_since: actix::clock::Instant,
+    side: Option<Side>,
+    profile: Profile,
+}
+
+impl UserConnectionInfo {
+    fn is_connected(&self) -> bool {
+        self.reconnect_timer.is_none()
+    }
 }
 
 struct AdminConnectionInfo {
     socket_recipient: Recipient<WebSocketMessage>,
+    protocol: ClientProtocol,
+    /// Whether the admin asked for copies of the room's chat messages.
+    moderating_chat: bool,
+}
+
+/// A match of two users, or a pod of more who all connect to each other.
+struct ActiveMatch {
+    id: Uuid,
+    users: Vec<Uuid>,
+    started_at: Instant,
+    /// Kept rather than when the match ends, which may be too far off for an `Instant`.
+    duration: Duration,
+    /// Ends the match once its duration is up; cancelled when the match ends early.
+    timer: SpawnHandle,
+}
+
+impl ActiveMatch {
+    fn includes(&self, id: &Uuid) -> bool {
+        self.users.contains(id)
+    }
+
+    fn partners_of<'a>(&'a self, id: &'a Uuid) -> impl Iterator<Item = &'a Uuid> {
+        self.users.iter().filter(move |user_id| *user_id != id)
+    }
 }
 
 pub struct Room {
     pub id: Uuid,
     pub name: String,
     pub admin_token: String,
+    /// Unix timestamp after which the room is closed regardless of activity.
+    pub expires_at: Option<u64>,
+    settings: RoomSettings,
+    match_strategy_kind: MatchStrategyKind,
+    match_strategy: Box<dyn MatchStrategy>,
+    reconnect_grace_period: Duration,
+    max_chat_message_length: usize,
+    /// When the last user or admin left, or when the room was created; `None` while occupied.
+    empty_since: Option<Instant>,
     admins: HashMap<Uuid, AdminConnectionInfo>,
     users: HashMap<Uuid, UserConnectionInfo>,
-    active_matches: Vec<(Uuid, Uuid)>,
-    previous_matches: Vec<(Uuid, Uuid)>,
+    active_matches: Vec<ActiveMatch>,
+    previous_matches: Vec<Vec<Uuid>>,
+    /// Users who are ready to be matched but had no free partner yet, longest waiting first.
+    ready_queue: VecDeque<Uuid>,
+    /// Set by admins to hold off matching queued users.
+    matching_paused: bool,
+    /// How many rounds were started in this room.
+    rounds_played: u32,
+    /// Starts the next round once the current one is over; set while rounds are running.
+    round_timer: Option<SpawnHandle>,
+    /// Announcements that haven't expired yet, which are also sent to users who join later.
+    announcements: Vec<Announcement>,
 }
 
 impl Room {
-    pub fn new(name: String) -> Room {
-        let random_string = repeat_with(fastrand::alphanumeric).take(32).collect();
-
+    pub fn new(
+        name: String,
+        expires_at: Option<u64>,
+        match_strategy: MatchStrategyKind,
+        config: &Config,
+    ) -> Room {
         Room {
             id: Uuid::new_v4(),
             name,
-            admin_token: random_string,
+            admin_token: random_token(),
+            expires_at,
+            settings: RoomSettings::default(),
+            match_strategy_kind: match_strategy,
+            match_strategy: match_strategy.strategy(),
+            reconnect_grace_period: config.reconnect_grace_period,
+            max_chat_message_length: config.max_chat_message_length,
+            empty_since: Some(Instant::now()),
             admins: HashMap::new(),
             users: HashMap::new(),
             active_matches: Vec::new(),
             previous_matches: Vec::new(),
+            ready_queue: VecDeque::new(),
+            matching_paused: false,
+            rounds_played: 0,
+            round_timer: None,
+            announcements: Vec::new(),
         }
     }
 
-    fn send_message(&self, message: &str, recipient_id: &Uuid) {
-        let do_send = |socket_recipient: &Recipient<WebSocketMessage>| {
-            let websocket_body = WebSocketMessage(message.to_owned());
-            socket_recipient.do_send(websocket_body);
-        };
-
+    fn send_message(&self, message: WebSocketMessage, recipient_id: &Uuid) {
         if let Some(connection_info) = self.users.get(recipient_id) {
-            do_send(&connection_info.socket_recipient);
+            connection_info.socket_recipient.do_send(message);
         } else if let Some(connection_info) = self.admins.get(recipient_id) {
-            do_send(&connection_info.socket_recipient);
+            connection_info.socket_recipient.do_send(message);
         } else {
             println!(
                 "❌ Attempted to send message but couldn't find user id {:?}!",
@@ -142,11 +287,104 @@ impl Room {
         }
     }
 
-    fn send_event(&self, event: Event, recipient_id: &Uuid) {
+    fn send_event(&self, event: ServerEvent, recipient_id: &Uuid) {
+        let protocol = self.protocol_of(recipient_id);
+        if let Some(capability) = event.required_capability() {
+            if protocol.is_some_and(|protocol| !protocol.supports(capability)) {
+                return;
+            }
+        }
         println!("✉️ Sending {:?} to {:?}.", event, recipient_id);
 
-        let json = serde_json::to_string_pretty(&event).unwrap();
-        self.send_message(&json, recipient_id);
+        let encoding = protocol
+            .map(|protocol| protocol.encoding)
+            .unwrap_or_default();
+        self.send_message(encoding.encode(&event), recipient_id);
+    }
+
+    fn protocol_of(&self, id: &Uuid) -> Option<&ClientProtocol> {
+        self.users
+            .get(id)
+            .map(|user| &user.protocol)
+            .or_else(|| self.admins.get(id).map(|admin| &admin.protocol))
+    }
+
+    fn send_self_joined(&self, id: &Uuid) {
+        if let Some(user) = self.users.get(id) {
+            self.send_event(
+                ServerEvent::SelfJoined {
+                    id: *id,
+                    resume_token: user
+                        .protocol
+                        .supports("resume")
+                        .then(|| user.resume_token.clone()),
+                    version: user.protocol.version,
+                    capabilities: user.protocol.capabilities.clone(),
+                },
+                id,
+            );
+        }
+    }
+
+    fn state(&self) -> RoomState {
+        RoomState {
+            id: self.id,
+            name: self.name.clone(),
+            settings: self.settings.clone(),
+            expires_at: self.expires_at,
+            match_strategy: self.match_strategy_kind,
+            users: self.users.len(),
+            admins: self.admins.len(),
+            active_matches: self.active_matches.len(),
+            previous_matches: self.previous_matches.len(),
+            matching_paused: self.matching_paused,
+            round: self.round_timer.map(|_| self.rounds_played),
+            group_balance: self.group_balance(),
+        }
+    }
+
+    fn group_balance(&self) -> GroupBalance {
+        let mut balance = GroupBalance::default();
+        for user in self.users.values() {
+            match user.side {
+                Some(Side::A) => balance.a += 1,
+                Some(Side::B) => balance.b += 1,
+                None => balance.unassigned += 1,
+            }
+        }
+        balance
+    }
+
+    /// Lets admins of rooms that only match users across groups know how many users are on
+    /// each side.
+    fn broadcast_group_balance(&self) {
+        if !self.settings.bipartite {
+            return;
+        }
+
+        let balance = self.group_balance();
+        for id in self.admins.keys() {
+            self.send_event(ServerEvent::GroupBalanceChanged { balance }, id);
+        }
+    }
+
+    fn is_expired(&self, idle_ttl: Duration) -> bool {
+        let is_past_expiry = self
+            .expires_at
+            .is_some_and(|expires_at| unix_timestamp() >= expires_at);
+        let is_idle = self
+            .empty_since
+            .is_some_and(|empty_since| empty_since.elapsed() >= idle_ttl);
+
+        is_past_expiry || is_idle
+    }
+
+    fn update_empty_since(&mut self) {
+        if self.users.is_empty() && self.admins.is_empty() {
+            self.empty_since.get_or_insert_with(Instant::now);
+        } else {
+            self.empty_since = None;
+        }
     }
 
     fn log_current_stats(&self) {
@@ -158,373 +396,1526 @@ impl Room {
         users: {:?} ({:?})
         active matches: {:?}
         previous matches: {:?}
+        ready queue: {:?}
         ",
             self.name,
             self.admins.keys().len(),
             self.admins.keys(),
             self.users.keys().len(),
             self.users.keys(),
-            self.active_matches,
-            self.previous_matches
+            self.active_pods(),
+            self.previous_matches,
+            self.ready_queue
         );
     }
 }
 
+fn parse_command<T: DeserializeOwned>(payload: serde_json::Value) -> Result<T, ClientError> {
+    serde_json::from_value(payload.clone()).map_err(|error| {
+        // point out when clients send events that only flow the other way
+        if serde_json::from_value::<ServerEvent>(payload).is_ok() {
+            ClientError::new(
+                ErrorCode::UnexpectedEvent,
+                "This event is only sent by the server",
+            )
+        } else {
+            ClientError::new(ErrorCode::InvalidEvent, error.to_string())
+        }
+    })
+}
+
+fn unknown_user(id: &Uuid) -> ClientError {
+    ClientError::new(
+        ErrorCode::UnknownRecipient,
+        format!("There is no user {} in this room", id),
+    )
+}
+
+/// Replaces every word of `text` that is in `blocked_words`, ignoring case, with as many
+/// asterisks as it has characters. Returns `None` if no word had to be masked.
+fn mask_words(text: &str, blocked_words: &[String]) -> Option<String> {
+    if blocked_words.is_empty() {
+        return None;
+    }
+
+    let mut masked = String::with_capacity(text.len());
+    let mut censored = false;
+    let mut word = String::new();
+    let mut flush = |word: &mut String, masked: &mut String| {
+        let lowercase = word.to_lowercase();
+        if blocked_words
+            .iter()
+            .any(|blocked_word| blocked_word.to_lowercase() == lowercase)
+        {
+            masked.extend(word.chars().map(|_| '*'));
+            censored = true;
+        } else {
+            masked.push_str(word);
+        }
+        word.clear();
+    };
+    for char in text.chars() {
+        if char.is_alphanumeric() {
+            word.push(char);
+        } else {
+            flush(&mut word, &mut masked);
+            masked.push(char);
+        }
+    }
+    flush(&mut word, &mut masked);
+
+    censored.then_some(masked)
+}
+
+fn random_token() -> String {
+    repeat_with(fastrand::alphanumeric).take(32).collect()
+}
+
 impl Actor for Room {
     type Context = Context<Self>;
 }
 
 impl Handler<ClientConnect> for Room {
-    type Result = ();
+    type Result = MessageResult<ClientConnect>;
 
-    fn handle(&mut self, msg: ClientConnect, _: &mut Context<Self>) -> Self::Result {
-        match msg.kind {
+    fn handle(&mut self, msg: ClientConnect, ctx: &mut Context<Self>) -> Self::Result {
+        let id = match msg.kind {
             ClientKind::Admin => {
                 // store the new admin
                 self.admins.insert(
                     msg.id,
                     AdminConnectionInfo {
                         socket_recipient: msg.addr,
+                        protocol: msg.protocol,
+                        moderating_chat: false,
                     },
                 );
 
                 // send to the admin all already present users
                 for (id, info) in self.users.iter() {
                     self.send_event(
-                        Event::UserPresent {
+                        ServerEvent::UserPresent {
                             id: *id,
                             name: info.name.clone(),
+                            side: info.side,
                         },
                         &msg.id,
                     );
                 }
-
-                // send to all admins in the room the currently active matches
-                self.admins.keys().for_each(|conn_id| {
+                if self.settings.bipartite {
                     self.send_event(
-                        Event::ActiveMatchesChanged {
-                            matches: self.active_matches.clone(),
+                        ServerEvent::GroupBalanceChanged {
+                            balance: self.group_balance(),
                         },
-                        conn_id,
+                        &msg.id,
                     );
-                });
+                }
+
+                // send to all admins in the room the currently active matches
+                self.broadcast_active_matches();
+
+                msg.id
             }
-            ClientKind::User(name) => {
+            ClientKind::User {
+                name,
+                resume_token,
+                side,
+                profile,
+            } => {
+                let resume_token = resume_token.filter(|_| msg.protocol.supports("resume"));
+                if let Some(id) = resume_token.and_then(|token| self.find_session(&token)) {
+                    self.resume_session(id, msg.id, msg.addr, msg.protocol, ctx);
+                    return self.finish_connect(id);
+                }
+
                 // store the new user
                 self.users.insert(
                     msg.id,
                     UserConnectionInfo {
                         name: name.clone(),
                         socket_recipient: msg.addr,
+                        protocol: msg.protocol,
+                        connection_id: msg.id,
+                        resume_token: random_token(),
+                        reconnect_timer: None,
+                        joined_at: Instant::now(),
+                        available_since: Instant::now(),
+                        ready_since: actix::clock::Instant::now(),
+                        side,
+                        profile,
                     },
                 );
 
                 // send the user their own ID
-                self.send_event(Event::SelfJoined { id: msg.id }, &msg.id);
+                self.send_self_joined(&msg.id);
+
+                // catch the user up on what admins announced before they joined
+                self.announcements
+                    .retain(|announcement| !announcement.is_expired());
+                for announcement in &self.announcements {
+                    self.send_event(announcement.event(), &msg.id);
+                }
 
                 // send to all admins in the room that the user joined
                 self.admins.keys().for_each(|conn_id| {
                     self.send_event(
-                        Event::UserJoined {
+                        ServerEvent::UserJoined {
                             id: msg.id,
                             name: name.clone(),
+                            side,
                         },
                         conn_id,
                     );
                 });
+                self.broadcast_group_balance();
+
+                // the new user might be a partner for someone who's waiting
+                self.process_queue(ctx);
+
+                msg.id
             }
-        }
+        };
 
-        self.log_current_stats();
+        self.finish_connect(id)
     }
 }
 
 impl Handler<ClientDisconnect> for Room {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientDisconnect, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: ClientDisconnect, ctx: &mut Context<Self>) -> Self::Result {
         // try selecting the client from all user users
-        if let Some(_) = self.users.remove(&msg.id) {
-            for (a, b) in &self.active_matches {
-                // send the other users in the user's active matches that their partner left
-                if a == &msg.id {
-                    self.send_event(Event::UserLeft { id: msg.id }, b);
-                } else if b == &msg.id {
-                    self.send_event(Event::UserLeft { id: msg.id }, a);
-                }
+        if let Some(user) = self.users.get_mut(&msg.id) {
+            if user.connection_id != msg.connection_id || !user.is_connected() {
+                // the user resumed their session on another connection in the meantime, or
+                // this connection is already known to be gone
+                return;
             }
-            self.active_matches
-                .retain(|(a, b)| a != &msg.id && b != &msg.id);
-            // send to all admins in the room the currently active matches
-            self.admins.keys().for_each(|conn_id| {
-                self.send_event(
-                    Event::ActiveMatchesChanged {
-                        matches: self.active_matches.clone(),
+
+            if msg.closed_deliberately
+                || self.reconnect_grace_period.is_zero()
+                || !user.protocol.supports("resume")
+            {
+                self.remove_user(msg.id, ctx);
+            } else {
+                // keep the user's place and match for a while so they can resume their session
+                let id = msg.id;
+                user.reconnect_timer = Some(ctx.run_later(
+                    self.reconnect_grace_period,
+                    move |room, ctx| {
+                        println!("⌛ {:?} didn't reconnect in time", id);
+                        room.remove_user(id, ctx);
+                        room.log_current_stats();
                     },
-                    conn_id,
-                );
-            });
+                ));
 
-            // send to all admins in the room that the user left
-            self.admins.keys().for_each(|conn_id| {
-                self.send_event(Event::UserLeft { id: msg.id }, conn_id);
-            });
+                for partner_id in self.partners_of(&msg.id) {
+                    self.send_event(ServerEvent::UserReconnecting { id: msg.id }, &partner_id);
+                }
+            }
         } else {
             // if the client wasn't among user users, it must have been an admin
             // remove the admin without notifying anyone
             self.admins.remove(&msg.id);
+            self.update_empty_since();
         }
 
         self.log_current_stats();
     }
 }
 
-impl Handler<ClientMessage> for Room {
-    type Result = ();
+impl Room {
+    fn finish_connect(&mut self, id: Uuid) -> MessageResult<ClientConnect> {
+        self.update_empty_since();
+        self.log_current_stats();
 
-    fn handle(&mut self, msg: ClientMessage, ctx: &mut Context<Self>) -> Self::Result {
-        let event: Result<Event, serde_json::Error> = serde_json::from_str(&msg.payload);
+        MessageResult(id)
+    }
 
-        match event {
-            Ok(Event::ICECandidate { id, description }) => self.send_event(
-                Event::ICECandidate {
-                    id: msg.id,
-                    description,
-                },
-                &id,
-            ),
-            Ok(Event::RTCConnectionOffer { id, description }) => self.send_event(
-                Event::RTCConnectionOffer {
-                    id: msg.id,
-                    description,
-                },
-                &id,
-            ),
-            Ok(Event::RTCConnectionAnswer { id, description }) => self.send_event(
-                Event::RTCConnectionAnswer {
-                    id: msg.id,
-                    description,
-                },
-                &id,
-            ),
-            Ok(Event::ReadyToMatch { id }) => {
-                match self.make_match(id) {
-                    Some((self_id, other_user_id)) => {
-                        if let Some(other_user) = self.users.get(&other_user_id) {
-                            let duration = if is_dev_mode() {
-                                MATCH_DURATION_DEV_MODE
-                            } else {
-                                MATCH_DURATION
-                            };
-                            // send the new user the Id of the other user to connect to
-                            self.send_event(
-                                Event::UserMatched {
-                                    id: other_user_id,
-                                    name: other_user.name.clone(),
-                                    duration: duration.as_secs(),
-                                },
-                                &self_id,
-                            );
-
-                            ctx.run_later(duration, move |a, _ctx| {
-                                a.send_event(Event::MatchEnded { id: other_user_id }, &self_id);
-                                a.send_event(Event::MatchEnded { id: self_id }, &other_user_id);
-
-                                a.active_matches.retain(|(a, b)| {
-                                    a != &self_id
-                                        && a != &other_user_id
-                                        && b != &self_id
-                                        && b != &other_user_id
-                                });
-                            });
-
-                            // send to all admins in the room the currently active matches
-                            self.admins.keys().for_each(|conn_id| {
-                                self.send_event(
-                                    Event::ActiveMatchesChanged {
-                                        matches: self.active_matches.clone(),
-                                    },
-                                    conn_id,
-                                );
-                            });
-                        } else {
-                            // this should probably throw or something
-                        }
-                    }
-                    None => (),
+    fn find_session(&self, resume_token: &str) -> Option<Uuid> {
+        self.users
+            .iter()
+            .find(|(_, user)| {
+                constant_time_eq(user.resume_token.as_bytes(), resume_token.as_bytes())
+            })
+            .map(|(id, _)| *id)
+    }
+
+    /// Reattaches a user to their existing session on a new connection, keeping their id, match
+    /// and place in the ready queue.
+    fn resume_session(
+        &mut self,
+        id: Uuid,
+        connection_id: Uuid,
+        socket_recipient: Recipient<WebSocketMessage>,
+        protocol: ClientProtocol,
+        ctx: &mut Context<Self>,
+    ) {
+        let user = match self.users.get_mut(&id) {
+            Some(user) => user,
+            None => return,
+        };
+        let previous_socket_recipient =
+            std::mem::replace(&mut user.socket_recipient, socket_recipient);
+        user.connection_id = connection_id;
+        user.protocol = protocol;
+        let was_disconnected = match user.reconnect_timer.take() {
+            Some(reconnect_timer) => {
+                ctx.cancel_future(reconnect_timer);
+                true
+            }
+            None => false,
+        };
+        if !was_disconnected {
+            // the old connection hasn't timed out yet but the user moved on from it
+            previous_socket_recipient.do_send(WebSocketMessage::Close(Some(CloseReason {
+                code: CloseCode::Normal,
+                description: Some("session resumed on another connection".to_string()),
+            })));
+        }
+
+        println!("🔁 {:?} resumed their session", id);
+
+        self.send_self_joined(&id);
+
+        // bring the user back up to date on their match so they can reconnect to their partners
+        if let Some(active_match) = self
+            .active_matches
+            .iter()
+            .find(|active_match| active_match.includes(&id))
+        {
+            let remaining = active_match
+                .duration
+                .saturating_sub(active_match.started_at.elapsed());
+            for partner_id in active_match.partners_of(&id) {
+                self.send_user_matched(&id, partner_id, remaining);
+                if was_disconnected {
+                    self.send_event(ServerEvent::UserReconnected { id }, partner_id);
                 }
             }
-            Ok(event) => println!("⚠️ Unexpected event: {:?}", event),
-            Err(_error) => println!("⚠️ Unknown message: {:?}", msg.payload),
+        }
+        if let Some(position) = self.queue_position(&id) {
+            self.send_event(ServerEvent::Queued { position }, &id);
         }
 
-        self.log_current_stats();
+        // the user can be matched again if they were waiting
+        self.process_queue(ctx);
     }
-}
 
-impl Room {
-    fn make_match(&mut self, new_user_id: Uuid) -> Option<(Uuid, Uuid)> {
-        let next_match = calculate_next_match::<UserConnectionInfo>(
-            &new_user_id,
-            &self.users,
-            &self.active_matches,
-            &self.previous_matches,
-        );
+    /// Removes a user from the room for good, ending their match and taking them out of the queue.
+    fn remove_user(&mut self, id: Uuid, ctx: &mut Context<Self>) {
+        if self.users.remove(&id).is_none() {
+            return;
+        }
 
-        match next_match {
-            None => return None,
-            Some(next_match) => {
-                self.active_matches.push(next_match);
-                self.previous_matches.push(next_match);
-                Some(next_match)
+        // send the other users in the user's active match that their partner left
+        for partner_id in self.partners_of(&id) {
+            self.send_event(ServerEvent::UserLeft { id }, &partner_id);
+        }
+        for active_match in self.active_matches.iter_mut() {
+            active_match.users.retain(|user_id| user_id != &id);
+        }
+        // a match is over once there's only one user left in it, so its timer must not fire for
+        // a user who might be in a new match by then
+        let (ended_matches, active_matches): (Vec<_>, Vec<_>) = self
+            .active_matches
+            .drain(..)
+            .partition(|active_match| active_match.users.len() < 2);
+        self.active_matches = active_matches;
+        for ended_match in ended_matches {
+            ctx.cancel_future(ended_match.timer);
+            for user_id in &ended_match.users {
+                if let Some(user) = self.users.get_mut(user_id) {
+                    user.available_since = Instant::now();
+                }
             }
         }
+        // send to all admins in the room the currently active matches
+        self.broadcast_active_matches();
+
+        // send to all admins in the room that the user left
+        self.admins.keys().for_each(|conn_id| {
+            self.send_event(ServerEvent::UserLeft { id }, conn_id);
+        });
+        self.broadcast_group_balance();
+
+        // the user's partner is free again and everyone queued behind the user moves up
+        let previous_queue = self.ready_queue.clone();
+        self.ready_queue.retain(|queued_id| queued_id != &id);
+        self.match_queued_users(ctx);
+        self.notify_queue_positions(&previous_queue);
+
+        self.update_empty_since();
     }
 }
 
-fn calculate_next_match<T>(
-    id: &Uuid,
-    ids_list: &HashMap<Uuid, T>,
-    participating_exclude_list: &Vec<(Uuid, Uuid)>,
-    match_exclude_list: &Vec<(Uuid, Uuid)>,
-) -> Option<(Uuid, Uuid)> {
-    // return None if the id to match is currently participating in a match
-    if let Some(_) = participating_exclude_list
-        .iter()
-        .find(|(a, b)| &a == &id || &b == &id)
-    {
-        return None;
+impl Handler<VerifyAdminToken> for Room {
+    type Result = bool;
+
+    fn handle(&mut self, msg: VerifyAdminToken, _: &mut Context<Self>) -> Self::Result {
+        constant_time_eq(msg.token.as_bytes(), self.admin_token.as_bytes())
     }
+}
 
-    let next_match_id = ids_list
-        .keys()
-        // only consider ids that are not the id to match
-        .filter(|_id| *_id != id)
-        // filter ids that are in an active match
-        .filter(|_id| {
-            !&participating_exclude_list
-                .iter()
-                .any(|(a, b)| &a == _id || &b == _id)
-        })
-        // filter matches that had been made before
-        .filter(|_id| {
-            !&match_exclude_list
-                .iter()
-                .any(|(a, b)| (&a == _id && &b == &id) || (&a == &id && &b == _id))
-        })
-        .next();
+impl Handler<GetRoomState> for Room {
+    type Result = MessageResult<GetRoomState>;
 
-    match next_match_id {
-        None => return None,
-        Some(next_match_id) => Some((*id, *next_match_id)),
+    fn handle(&mut self, _: GetRoomState, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.state())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::calculate_next_match;
-    use std::collections::HashMap;
-    use uuid::{uuid, Uuid};
+impl Handler<UpdateRoom> for Room {
+    type Result = MessageResult<UpdateRoom>;
 
-    const USER1_ID: Uuid = uuid!("11111111-06c9-4f14-bf8b-fafce92d6396");
-    const USER2_ID: Uuid = uuid!("22222222-06c9-4f14-bf8b-fafce92d6396");
-    const USER3_ID: Uuid = uuid!("33333333-06c9-4f14-bf8b-fafce92d6396");
-    const USER4_ID: Uuid = uuid!("44444444-06c9-4f14-bf8b-fafce92d6396");
+    fn handle(&mut self, msg: UpdateRoom, _: &mut Context<Self>) -> Self::Result {
+        if let Some(name) = msg.name {
+            self.name = name;
+        }
+        if let Some(settings) = msg.settings {
+            let was_bipartite = self.settings.bipartite;
+            self.settings.apply(settings);
+            // admins only learn about the balance of rooms that match across groups
+            if self.settings.bipartite && !was_bipartite {
+                self.broadcast_group_balance();
+            }
+        }
 
-    #[test]
-    fn it_makes_matches_correctly() {
-        let mut users = HashMap::<Uuid, ()>::new();
-        let active_matches = Vec::<(Uuid, Uuid)>::new();
-        let previous_matches = Vec::<(Uuid, Uuid)>::new();
+        self.log_current_stats();
 
-        users.insert(USER1_ID, ());
-        users.insert(USER2_ID, ());
+        MessageResult(self.state())
+    }
+}
 
-        // if there are only 2 users with no active or previous, it matches those together
-        let next_match =
-            calculate_next_match(&USER1_ID, &users, &active_matches, &previous_matches);
+impl Handler<Announce> for Room {
+    type Result = Result<Announcement, String>;
 
-        assert_eq!(next_match, Some((USER1_ID, USER2_ID)));
+    fn handle(&mut self, msg: Announce, _: &mut Context<Self>) -> Self::Result {
+        self.announce(msg.message, msg.severity, msg.expires_at)
+            .map_err(|error| error.message)
     }
+}
 
-    #[test]
-    fn it_excludes_active_matches() {
-        let mut users = HashMap::<Uuid, ()>::new();
-        let mut active_matches = Vec::<(Uuid, Uuid)>::new();
-        let previous_matches = Vec::<(Uuid, Uuid)>::new();
+impl Handler<CloseRoom> for Room {
+    type Result = ();
 
-        users.insert(USER1_ID, ());
-        users.insert(USER2_ID, ());
-        users.insert(USER3_ID, ());
+    fn handle(&mut self, _: CloseRoom, ctx: &mut Context<Self>) -> Self::Result {
+        // let everyone know the room is going away before their sockets are closed
+        for id in self.users.keys().chain(self.admins.keys()) {
+            self.send_event(ServerEvent::RoomClosed, id);
+            self.send_message(
+                WebSocketMessage::Close(Some(CloseReason {
+                    code: CloseCode::Away,
+                    description: Some("room closed".to_string()),
+                })),
+                id,
+            );
+        }
 
-        active_matches.push((USER1_ID, USER2_ID));
+        println!("🗑️ Closing room {:?} ({:?})", self.name, self.id);
 
-        // if there are 3 users and 2 are in an active match, it cannot match the third user
-        let next_match =
-            calculate_next_match(&USER3_ID, &users, &active_matches, &previous_matches);
+        ctx.stop();
+    }
+}
 
-        assert_eq!(next_match, None);
+impl Handler<IsRoomExpired> for Room {
+    type Result = bool;
 
-        // if the user is in an active match, it cannot be matched again
-        let next_match =
-            calculate_next_match(&USER1_ID, &users, &active_matches, &previous_matches);
+    fn handle(&mut self, msg: IsRoomExpired, _: &mut Context<Self>) -> Self::Result {
+        self.is_expired(msg.idle_ttl)
+    }
+}
 
-        assert_eq!(next_match, None);
+impl Handler<ClientMessage> for Room {
+    type Result = ();
 
-        // if there are 4 users and 2 are in an active match, only one option remains
-        users.insert(USER4_ID, ());
-        let next_match =
-            calculate_next_match(&USER3_ID, &users, &active_matches, &previous_matches);
+    fn handle(&mut self, msg: ClientMessage, ctx: &mut Context<Self>) -> Self::Result {
+        let protocol = self.protocol_of(&msg.id).cloned().unwrap_or_default();
+        let payload = match protocol.encoding.decode(&msg.payload) {
+            Ok(payload) => protocol.upgrade_command(payload),
+            Err(error) => {
+                println!(
+                    "⚠️ Unknown message: {:?}",
+                    String::from_utf8_lossy(&msg.payload)
+                );
+                self.send_error(
+                    ClientError::new(protocol.encoding.invalid_payload_code(), error),
+                    None,
+                    &msg.id,
+                );
+                return;
+            }
+        };
+        let correlation_id = payload
+            .get("correlation_id")
+            .and_then(|correlation_id| correlation_id.as_str())
+            .map(String::from);
+
+        let result = if self.admins.contains_key(&msg.id) {
+            parse_command(payload).and_then(|command| {
+                self.handle_admin_command(command, msg.id, correlation_id.clone(), ctx)
+            })
+        } else {
+            parse_command(payload).and_then(|command| self.handle_command(command, msg.id, ctx))
+        };
+        if let Err(error) = result {
+            println!("⚠️ Rejected message from {:?}: {:?}", msg.id, error);
+            self.send_error(error, correlation_id, &msg.id);
+        }
 
-        assert_eq!(next_match, Some((USER3_ID, USER4_ID)));
+        self.log_current_stats();
     }
+}
 
-    #[test]
-    fn it_does_not_repeat_matches() {
-        let mut users = HashMap::<Uuid, ()>::new();
-        let active_matches = Vec::<(Uuid, Uuid)>::new();
-        let mut previous_matches = Vec::<(Uuid, Uuid)>::new();
-
-        users.insert(USER1_ID, ());
-        users.insert(USER2_ID, ());
-        users.insert(USER3_ID, ());
+impl Room {
+    fn handle_command(
+        &mut self,
+        command: ClientCommand,
+        sender_id: Uuid,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), ClientError> {
+        match command {
+            ClientCommand::ICECandidate { id, description } => self.relay(
+                ServerEvent::ICECandidate {
+                    id: sender_id,
+                    description,
+                },
+                &sender_id,
+                &id,
+            ),
+            ClientCommand::RTCConnectionOffer { id, description } => self.relay(
+                ServerEvent::RTCConnectionOffer {
+                    id: sender_id,
+                    description,
+                },
+                &sender_id,
+                &id,
+            ),
+            ClientCommand::RTCConnectionAnswer { id, description } => self.relay(
+                ServerEvent::RTCConnectionAnswer {
+                    id: sender_id,
+                    description,
+                },
+                &sender_id,
+                &id,
+            ),
+            ClientCommand::ChatMessage { text } => self.relay_chat_message(text, sender_id),
+            // always match the user who sent the message, never anyone named in the payload
+            ClientCommand::ReadyToMatch => self.enqueue(sender_id, ctx),
+            ClientCommand::SkipMatch => self.leave_match(sender_id, ctx),
+        }
+    }
 
-        // Sorting isn't stable so it either matches user 3 with user 2 first and then user 1 or with user 1 first and then user 2. After those 2 matches it must return None as there are no options left.
-        // Running this 100 times to make sure both cases are covered
-        let mut i = 1;
-        while i <= 100 {
-            previous_matches.clear();
+    fn handle_admin_command(
+        &mut self,
+        command: AdminCommand,
+        admin_id: Uuid,
+        correlation_id: Option<String>,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), ClientError> {
+        println!("🛠️ {:?} sent {:?}", admin_id, command);
+
+        match command.clone() {
+            AdminCommand::KickUser { id, reason } => {
+                if !self.users.contains_key(&id) {
+                    return Err(unknown_user(&id));
+                }
+                let mut description = match reason {
+                    Some(reason) => format!("kicked by an admin: {}", reason),
+                    None => "kicked by an admin".to_string(),
+                };
+                // close frames can't carry more than 125 bytes, including the close code
+                truncate_to_char_boundary(&mut description, MAX_CLOSE_REASON_LENGTH);
+                self.send_message(
+                    WebSocketMessage::Close(Some(CloseReason {
+                        code: CloseCode::Policy,
+                        description: Some(description),
+                    })),
+                    &id,
+                );
+                self.remove_user(id, ctx);
+            }
+            AdminCommand::EndMatch { id } => {
+                let active_match = self
+                    .active_matches
+                    .iter()
+                    .find(|active_match| active_match.includes(&id))
+                    .ok_or_else(|| {
+                        ClientError::new(ErrorCode::NotMatched, format!("{} is not in a match", id))
+                    })?;
+                self.end_match(active_match.id, ctx);
+            }
+            AdminCommand::EndAllMatches => {
+                let match_ids: Vec<Uuid> = self
+                    .active_matches
+                    .iter()
+                    .map(|active_match| active_match.id)
+                    .collect();
+                for match_id in match_ids {
+                    self.end_match(match_id, ctx);
+                }
+            }
+            AdminCommand::PairUsers { users: (a, b) } => self.pair_users(a, b, ctx)?,
+            AdminCommand::PauseMatching => self.matching_paused = true,
+            AdminCommand::ResumeMatching => {
+                self.matching_paused = false;
+                self.process_queue(ctx);
+            }
+            AdminCommand::StartRound => self.start_round(ctx),
+            AdminCommand::StopRounds => {
+                if let Some(timer) = self.round_timer.take() {
+                    ctx.cancel_future(timer);
+                }
+                self.process_queue(ctx);
+            }
+            AdminCommand::ModerateChat { enabled } => {
+                if let Some(admin) = self.admins.get_mut(&admin_id) {
+                    admin.moderating_chat = enabled;
+                }
+            }
+            AdminCommand::Announce {
+                message,
+                severity,
+                expires_at,
+            } => {
+                self.announce(message, severity, expires_at)?;
+            }
+        }
+
+        self.send_event(
+            ServerEvent::CommandAcknowledged {
+                command: command.clone(),
+                correlation_id,
+            },
+            &admin_id,
+        );
+        for id in self.admins.keys().filter(|id| **id != admin_id) {
+            self.send_event(
+                ServerEvent::AdminCommandExecuted {
+                    id: admin_id,
+                    command: command.clone(),
+                },
+                id,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Ends all matches and matches as many of the connected users as possible with someone
+    /// they haven't met yet. Pairs follow a round-robin schedule so that everyone meets everyone
+    /// if the same users stay for all rounds; pods are filled with users who met the fewest of
+    /// their members.
+    fn start_round(&mut self, ctx: &mut Context<Self>) {
+        if let Some(timer) = self.round_timer.take() {
+            ctx.cancel_future(timer);
+        }
+        let match_ids: Vec<Uuid> = self
+            .active_matches
+            .iter()
+            .map(|active_match| active_match.id)
+            .collect();
+        // queued users must not be matched in between, or they'd be in two matches at once
+        for match_id in match_ids {
+            self.close_match(match_id, ctx);
+        }
 
-            match calculate_next_match(&USER3_ID, &users, &active_matches, &previous_matches) {
-                Some((USER3_ID, USER2_ID)) => {
-                    previous_matches.push((USER3_ID, USER2_ID));
+        let mut participants: Vec<Uuid> = self
+            .users
+            .iter()
+            .filter(|(id, user)| user.is_connected() && !self.is_in_match(id))
+            .map(|(id, _)| *id)
+            .collect();
+        // the schedule only carries on from round to round if the seating is the same each time
+        participants.sort();
+        let pods = if self.settings.bipartite {
+            let on_side = |side: Side| -> Vec<Uuid> {
+                participants
+                    .iter()
+                    .filter(|id| {
+                        self.users
+                            .get(id)
+                            .is_some_and(|user| user.side == Some(side))
+                    })
+                    .copied()
+                    .collect()
+            };
+            next_bipartite_round_pairs(&on_side(Side::A), &on_side(Side::B), &self.previous_matches)
+                .into_iter()
+                .map(|(a, b)| vec![a, b])
+                .collect()
+        } else if self.settings.pod_size <= 2 {
+            next_round_pairs(&participants, &self.previous_matches)
+                .into_iter()
+                .map(|(a, b)| vec![a, b])
+                .collect()
+        } else {
+            group_into_pods(
+                &participants,
+                self.settings.pod_size,
+                &self.previous_matches,
+            )
+        };
+        let unpaired: Vec<Uuid> = participants
+            .into_iter()
+            .filter(|id| !pods.iter().any(|pod| pod.contains(id)))
+            .collect();
+
+        let has_new_pairs = pods.iter().any(|pod| {
+            pod.iter().enumerate().any(|(i, a)| {
+                pod[i + 1..]
+                    .iter()
+                    .any(|b| !have_met(&self.previous_matches, a, b))
+            })
+        });
+        if !has_new_pairs {
+            println!(
+                "🏁 Everyone in room {:?} met everyone after {} rounds",
+                self.name, self.rounds_played
+            );
+            for id in self.admins.keys() {
+                self.send_event(
+                    ServerEvent::RoundsFinished {
+                        rounds: self.rounds_played,
+                    },
+                    id,
+                );
+            }
+            self.process_queue(ctx);
+            return;
+        }
 
-                    let next_match =
-                        calculate_next_match(&USER3_ID, &users, &active_matches, &previous_matches);
+        self.rounds_played += 1;
+        let round = self.rounds_played;
+        println!(
+            "🔔 Starting round {} in room {:?} with {} matches",
+            round,
+            self.name,
+            pods.len()
+        );
+        let previous_queue = self.ready_queue.clone();
+        self.ready_queue
+            .retain(|id| !pods.iter().any(|pod| pod.contains(id)));
+        for pod in pods {
+            self.previous_matches.push(pod.clone());
+            self.start_match(pod, ctx);
+        }
+        self.notify_queue_positions(&previous_queue);
 
-                    assert_eq!(next_match, Some((USER3_ID, USER1_ID)));
-                    previous_matches.push((USER3_ID, USER1_ID));
+        for id in &unpaired {
+            self.send_event(ServerEvent::RoundBye { round }, id);
+        }
+        for id in self.admins.keys() {
+            self.send_event(
+                ServerEvent::RoundStarted {
+                    round,
+                    duration: self.settings.match_duration,
+                    byes: unpaired.clone(),
+                },
+                id,
+            );
+        }
 
-                    let next_match =
-                        calculate_next_match(&USER3_ID, &users, &active_matches, &previous_matches);
+        let duration = Duration::from_secs(self.settings.match_duration);
+        self.round_timer = Some(ctx.run_later(duration, |room, ctx| room.start_round(ctx)));
+    }
 
-                    assert_eq!(next_match, None);
+    /// Sends an announcement to all users and keeps it for users who join until it expires.
+    fn announce(
+        &mut self,
+        message: String,
+        severity: Severity,
+        expires_at: Option<u64>,
+    ) -> Result<Announcement, ClientError> {
+        if message.trim().is_empty() {
+            return Err(ClientError::new(
+                ErrorCode::InvalidEvent,
+                "Announcements can't be empty",
+            ));
+        }
+        if expires_at.is_some_and(|expires_at| expires_at <= unix_timestamp()) {
+            return Err(ClientError::new(
+                ErrorCode::InvalidEvent,
+                "expires_at must be in the future",
+            ));
+        }
+
+        let announcement = Announcement {
+            id: Uuid::new_v4(),
+            message,
+            severity,
+            expires_at,
+        };
+        println!("📣 Announcing {:?} in room {:?}", announcement, self.name);
+        for id in self.users.keys() {
+            self.send_event(announcement.event(), id);
+        }
+        if expires_at.is_some() {
+            self.announcements
+                .retain(|announcement| !announcement.is_expired());
+            self.announcements.push(announcement.clone());
+        }
+
+        Ok(announcement)
+    }
+
+    /// Matches two users on an admin's behalf, taking them out of the ready queue.
+    fn pair_users(&mut self, a: Uuid, b: Uuid, ctx: &mut Context<Self>) -> Result<(), ClientError> {
+        if a == b {
+            return Err(ClientError::new(
+                ErrorCode::InvalidEvent,
+                "A user can't be paired with themselves",
+            ));
+        }
+        for id in [a, b] {
+            if !self.users.get(&id).is_some_and(|user| user.is_connected()) {
+                return Err(unknown_user(&id));
+            }
+            if self.is_in_match(&id) {
+                return Err(ClientError::new(
+                    ErrorCode::AlreadyMatched,
+                    format!("{} is already in a match", id),
+                ));
+            }
+        }
+
+        let previous_queue = self.ready_queue.clone();
+        self.ready_queue.retain(|id| id != &a && id != &b);
+        self.previous_matches.push(vec![a, b]);
+        self.start_match(vec![a, b], ctx);
+        self.notify_queue_positions(&previous_queue);
+
+        Ok(())
+    }
+
+    fn send_error(&self, error: ClientError, correlation_id: Option<String>, recipient_id: &Uuid) {
+        self.send_event(
+            ServerEvent::Error {
+                code: error.code,
+                message: error.message,
+                correlation_id,
+            },
+            recipient_id,
+        );
+    }
+
+    /// Adds a user to the ready queue and matches everyone waiting for whom a partner is free.
+    fn enqueue(&mut self, id: Uuid, ctx: &mut Context<Self>) -> Result<(), ClientError> {
+        if self.settings.bipartite && self.users.get(&id).is_some_and(|user| user.side.is_none()) {
+            return Err(ClientError::new(
+                ErrorCode::SideRequired,
+                "This room matches users across groups, join with side a or b to be matched",
+            ));
+        }
+        if self.is_in_match(&id) {
+            return Err(ClientError::new(
+                ErrorCode::AlreadyMatched,
+                "You are already in a match",
+            ));
+        }
+        if let Some(position) = self.queue_position(&id) {
+            self.send_event(ServerEvent::Queued { position }, &id);
+            return Ok(());
+        }
+
+        let previous_queue = self.ready_queue.clone();
+        if let Some(user) = self.users.get_mut(&id) {
+            user.ready_since = actix::clock::Instant::now();
+        }
+        self.ready_queue.push_back(id);
+        self.match_queued_users(ctx);
+        self.notify_queue_positions(&previous_queue);
+
+        // let the user know they are waiting if no partner was available
+        if let Some(position) = self.queue_position(&id) {
+            self.send_event(ServerEvent::Queued { position }, &id);
+            if !self.settings.bipartite && self.settings.pod_size > 2 {
+                // try again once the user may be matched with fewer users
+                ctx.run_later(POD_FILL_TIMEOUT, |room, ctx| room.process_queue(ctx));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Matches queued users after a partner might have become available, e.g. when a user
+    /// joined or a match ended.
+    fn process_queue(&mut self, ctx: &mut Context<Self>) {
+        let previous_queue = self.ready_queue.clone();
+        self.match_queued_users(ctx);
+        self.notify_queue_positions(&previous_queue);
+    }
+
+    fn match_queued_users(&mut self, ctx: &mut Context<Self>) {
+        // while rounds are running, users are only matched when a round starts
+        if self.matching_paused || self.round_timer.is_some() {
+            return;
+        }
+
+        // users who waited the longest get to pick first
+        let mut i = 0;
+        while i < self.ready_queue.len() {
+            // users who are reconnecting keep their place but can't be matched until they're back
+            let is_connected = self
+                .users
+                .get(&self.ready_queue[i])
+                .is_some_and(|user| user.is_connected());
+            if !is_connected {
+                i += 1;
+                continue;
+            }
+
+            match self.make_match(self.ready_queue[i]) {
+                Some(users) => {
+                    self.ready_queue.retain(|id| !users.contains(id));
+                    self.start_match(users, ctx);
+                    // a pod can take in users queued before the current one who couldn't fill
+                    // one themselves, so go through the queue again
+                    i = 0;
                 }
-                Some((USER3_ID, USER1_ID)) => {
-                    previous_matches.push((USER3_ID, USER1_ID));
+                None => i += 1,
+            }
+        }
+    }
+
+    fn notify_queue_positions(&self, previous_queue: &VecDeque<Uuid>) {
+        for (index, id) in self.ready_queue.iter().enumerate() {
+            let previous_index = previous_queue.iter().position(|queued_id| queued_id == id);
+            if previous_index.is_some_and(|previous_index| previous_index != index) {
+                self.send_event(
+                    ServerEvent::QueuePosition {
+                        position: index + 1,
+                    },
+                    id,
+                );
+            }
+        }
+    }
+
+    fn queue_position(&self, id: &Uuid) -> Option<usize> {
+        self.ready_queue
+            .iter()
+            .position(|queued_id| queued_id == id)
+            .map(|index| index + 1)
+    }
+
+    /// Forwards a signaling event to the sender's current match partner only, so users can't
+    /// push offers or candidates to anyone else in the room.
+    fn relay(
+        &self,
+        event: ServerEvent,
+        sender_id: &Uuid,
+        recipient_id: &Uuid,
+    ) -> Result<(), ClientError> {
+        if !self.users.contains_key(recipient_id) {
+            return Err(ClientError::new(
+                ErrorCode::UnknownRecipient,
+                format!("There is no user {} in this room", recipient_id),
+            ));
+        }
+        if !self.are_matched(sender_id, recipient_id) {
+            return Err(ClientError::new(
+                ErrorCode::NotMatched,
+                format!("You are not matched with {}", recipient_id),
+            ));
+        }
+
+        self.send_event(event, recipient_id);
+        Ok(())
+    }
+
+    /// Sends a chat message to the sender's current match partner, masking the room's blocked
+    /// words, and a copy of it to the admins who moderate the chat.
+    fn relay_chat_message(&self, text: String, sender_id: Uuid) -> Result<(), ClientError> {
+        if text.trim().is_empty() {
+            return Err(ClientError::new(
+                ErrorCode::InvalidEvent,
+                "Chat messages can't be empty",
+            ));
+        }
+        if text.chars().count() > self.max_chat_message_length {
+            return Err(ClientError::new(
+                ErrorCode::MessageTooLong,
+                format!(
+                    "Chat messages can't be longer than {} characters",
+                    self.max_chat_message_length
+                ),
+            ));
+        }
+        let partner_ids = self.partners_of(&sender_id);
+        if partner_ids.is_empty() {
+            return Err(ClientError::new(
+                ErrorCode::NotMatched,
+                "You are not in a match",
+            ));
+        }
+        let partner_ids: Vec<Uuid> = partner_ids
+            .into_iter()
+            .filter(|id| {
+                self.protocol_of(id)
+                    .is_some_and(|protocol| protocol.supports("chat"))
+            })
+            .collect();
+        if partner_ids.is_empty() {
+            return Err(ClientError::new(
+                ErrorCode::ChatUnsupported,
+                "Your partners can't receive chat messages",
+            ));
+        }
+
+        let sent_at = unix_timestamp_millis();
+        let censored_text = mask_words(&text, &self.settings.blocked_words);
+        for partner_id in &partner_ids {
+            self.send_event(
+                ServerEvent::ChatMessage {
+                    id: sender_id,
+                    text: censored_text.clone().unwrap_or_else(|| text.clone()),
+                    sent_at,
+                },
+                partner_id,
+            );
+        }
+        for (id, _) in self
+            .admins
+            .iter()
+            .filter(|(_, admin)| admin.moderating_chat)
+        {
+            self.send_event(
+                ServerEvent::ChatMessageCopy {
+                    id: sender_id,
+                    recipient_ids: partner_ids.clone(),
+                    text: text.clone(),
+                    sent_at,
+                    censored: censored_text.is_some(),
+                },
+                id,
+            );
+        }
+
+        Ok(())
+    }
+
+    fn are_matched(&self, id: &Uuid, other_id: &Uuid) -> bool {
+        id != other_id
+            && self
+                .active_matches
+                .iter()
+                .any(|active_match| active_match.includes(id) && active_match.includes(other_id))
+    }
+
+    /// Everyone else in the user's match, none if they aren't in one.
+    fn partners_of(&self, id: &Uuid) -> Vec<Uuid> {
+        self.active_matches
+            .iter()
+            .find(|active_match| active_match.includes(id))
+            .map(|active_match| active_match.partners_of(id).copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn is_in_match(&self, id: &Uuid) -> bool {
+        self.active_matches
+            .iter()
+            .any(|active_match| active_match.includes(id))
+    }
+
+    fn active_pods(&self) -> Vec<Vec<Uuid>> {
+        self.active_matches
+            .iter()
+            .map(|active_match| active_match.users.clone())
+            .collect()
+    }
+
+    fn broadcast_active_matches(&self) {
+        let matches = self.active_pods();
+        self.admins.keys().for_each(|conn_id| {
+            self.send_event(
+                ServerEvent::ActiveMatchesChanged {
+                    matches: matches.clone(),
+                },
+                conn_id,
+            );
+        });
+    }
+
+    fn start_match(&mut self, users: Vec<Uuid>, ctx: &mut Context<Self>) {
+        let duration = Duration::from_secs(self.settings.match_duration);
+        // send every user the Id of each other user to connect to
+        for id in &users {
+            for partner_id in users.iter().filter(|partner_id| *partner_id != id) {
+                self.send_user_matched(id, partner_id, duration);
+            }
+        }
+
+        let match_id = Uuid::new_v4();
+        let timer = ctx.run_later(duration, move |room, ctx| room.end_match(match_id, ctx));
+        self.active_matches.push(ActiveMatch {
+            id: match_id,
+            users,
+            started_at: Instant::now(),
+            duration,
+            timer,
+        });
+
+        // send to all admins in the room the currently active matches
+        self.broadcast_active_matches();
+    }
+
+    fn send_user_matched(&self, id: &Uuid, partner_id: &Uuid, duration: Duration) {
+        if let Some(partner) = self.users.get(partner_id) {
+            let supports_roles = self
+                .protocol_of(id)
+                .is_some_and(|protocol| protocol.supports("match-roles"));
+            self.send_event(
+                ServerEvent::UserMatched {
+                    id: *partner_id,
+                    name: partner.name.clone(),
+                    duration: duration.as_secs(),
+                    role: supports_roles.then(|| MatchRole::for_pair(id, partner_id)),
+                },
+                id,
+            );
+        }
+    }
+
+    /// Ends an active match, either because its time is up or early, and lets all its users know.
+    fn end_match(&mut self, match_id: Uuid, ctx: &mut Context<Self>) {
+        self.close_match(match_id, ctx);
+
+        // the users are free again so queued users might be matched with them
+        self.process_queue(ctx);
+    }
+
+    /// Ends a match without matching queued users with its users, for when they're about to be
+    /// matched differently.
+    fn close_match(&mut self, match_id: Uuid, ctx: &mut Context<Self>) {
+        let index = match self
+            .active_matches
+            .iter()
+            .position(|active_match| active_match.id == match_id)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        let ended_match = self.active_matches.remove(index);
+        // a no-op if the timer is what ended the match
+        ctx.cancel_future(ended_match.timer);
+
+        for id in &ended_match.users {
+            if let Some(user) = self.users.get_mut(id) {
+                user.available_since = Instant::now();
+            }
+            for partner_id in ended_match.partners_of(id) {
+                self.send_event(ServerEvent::MatchEnded { id: *partner_id }, id);
+            }
+        }
+
+        // send to all admins in the room the currently active matches
+        self.broadcast_active_matches();
+    }
+
+    /// Takes a user out of their match, e.g. when they skip it. A pod goes on without them as
+    /// long as at least two users are left in it; otherwise the match ends.
+    fn leave_match(&mut self, id: Uuid, ctx: &mut Context<Self>) -> Result<(), ClientError> {
+        let active_match = self
+            .active_matches
+            .iter_mut()
+            .find(|active_match| active_match.includes(&id))
+            .ok_or_else(|| ClientError::new(ErrorCode::NotMatched, "You are not in a match"))?;
+        if active_match.users.len() <= 2 {
+            let match_id = active_match.id;
+            self.end_match(match_id, ctx);
+            return Ok(());
+        }
+
+        active_match.users.retain(|user_id| user_id != &id);
+        let partner_ids = active_match.users.clone();
+        for partner_id in &partner_ids {
+            self.send_event(ServerEvent::MatchEnded { id }, partner_id);
+            self.send_event(ServerEvent::MatchEnded { id: *partner_id }, &id);
+        }
+        if let Some(user) = self.users.get_mut(&id) {
+            user.available_since = Instant::now();
+        }
+        self.broadcast_active_matches();
+        self.process_queue(ctx);
+
+        Ok(())
+    }
+
+    /// How many users a match for `id` may have. Pods are only formed when they can be filled,
+    /// unless the user has been waiting for `POD_FILL_TIMEOUT`, just like the last pod of a round
+    /// may have fewer users.
+    fn pod_sizes(&self, id: &Uuid) -> RangeInclusive<usize> {
+        if self.settings.bipartite {
+            return 2..=2;
+        }
+        let pod_size = self.settings.pod_size;
+        let waited_long = self
+            .users
+            .get(id)
+            .is_some_and(|user| user.ready_since.elapsed() >= POD_FILL_TIMEOUT);
+        if waited_long {
+            2..=pod_size
+        } else {
+            pod_size..=pod_size
+        }
+    }
+
+    fn make_match(&mut self, new_user_id: Uuid) -> Option<Vec<Uuid>> {
+        let connected_users: HashMap<Uuid, Candidate> = self
+            .users
+            .iter()
+            .filter(|(_, user)| user.is_connected())
+            .map(|(id, user)| {
+                let candidate = Candidate {
+                    id: *id,
+                    joined_at: user.joined_at,
+                    available_since: user.available_since,
+                    match_count: self
+                        .previous_matches
+                        .iter()
+                        .filter(|users| users.contains(id))
+                        .count(),
+                    side: user.side,
+                    profile: user.profile.clone(),
+                };
+                (*id, candidate)
+            })
+            .collect();
+        let next_match = calculate_next_match(
+            &new_user_id,
+            &connected_users,
+            &self.active_pods(),
+            &self.previous_matches,
+            self.pod_sizes(&new_user_id),
+            self.settings.bipartite,
+            self.match_strategy.as_ref(),
+        );
+
+        // the caller is expected to start the match right away, which makes it active
+        let next_match = next_match?;
+        self.previous_matches.push(next_match.clone());
+        Some(next_match)
+    }
+}
+
+/// Finds as many partners for `id` as `pod_sizes` allows, and at least enough for its smallest
+/// size, among the users who aren't in a match and haven't met `id` before, and are on the other
+/// side if the match is `bipartite`. Each further partner is one who met the fewest of the
+/// partners picked so far, chosen by `strategy` for `id` among those.
+fn calculate_next_match(
+    id: &Uuid,
+    ids_list: &HashMap<Uuid, Candidate>,
+    participating_exclude_list: &[Vec<Uuid>],
+    match_exclude_list: &[Vec<Uuid>],
+    pod_sizes: RangeInclusive<usize>,
+    bipartite: bool,
+    strategy: &dyn MatchStrategy,
+) -> Option<Vec<Uuid>> {
+    // return None if the id to match is currently participating in a match
+    if participating_exclude_list
+        .iter()
+        .any(|users| users.contains(id))
+    {
+        return None;
+    }
+
+    let user = ids_list.get(id)?;
+    let side = user.side;
+    let mut candidates: Vec<Candidate> = ids_list
+        .iter()
+        // only consider ids that are not the id to match
+        .filter(|(_id, _)| *_id != id)
+        // filter ids that are in an active match
+        .filter(|(_id, _)| {
+            !&participating_exclude_list
+                .iter()
+                .any(|users| users.contains(_id))
+        })
+        // filter matches that had been made before
+        .filter(|(_id, _)| !have_met(match_exclude_list, _id, id))
+        // only match users across sides in bipartite rooms
+        .filter(|(_, candidate)| {
+            !bipartite || (side.is_some() && candidate.side.is_some() && candidate.side != side)
+        })
+        .map(|(_, candidate)| candidate.clone())
+        .collect();
+    if candidates.len() + 1 < *pod_sizes.start() {
+        return None;
+    }
+
+    let mut users = vec![*id];
+    while users.len() < *pod_sizes.end() && !candidates.is_empty() {
+        let overlap = |candidate: &Candidate| {
+            users
+                .iter()
+                .filter(|user_id| have_met(match_exclude_list, user_id, &candidate.id))
+                .count()
+        };
+        let least_overlap = candidates.iter().map(overlap).min()?;
+        let least_overlapping: Vec<Candidate> = candidates
+            .iter()
+            .filter(|candidate| overlap(candidate) == least_overlap)
+            .cloned()
+            .collect();
+        let Some(partner_id) = strategy.pick_partner(user, &least_overlapping) else {
+            break;
+        };
+        candidates.retain(|candidate| candidate.id != partner_id);
+        users.push(partner_id);
+    }
+
+    (users.len() >= *pod_sizes.start()).then_some(users)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        calculate_next_match, mask_words, MatchRole, Room, RoomSettingsUpdate, POD_FILL_TIMEOUT,
+    };
+    use crate::config::Config;
+    use crate::handshake::ClientProtocol;
+    use crate::matching::{Candidate, MatchStrategyKind, Profile, Random};
+    use crate::messages::{
+        ClientConnect, ClientDisconnect, ClientMessage, UpdateRoom, WebSocketMessage,
+    };
+    use crate::protocol::Side;
+    use crate::util::unix_timestamp;
+    use actix::{clock::sleep, Actor, Addr, Context, Handler};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::time;
+    use uuid::{uuid, Uuid};
+
+    const USER1_ID: Uuid = uuid!("11111111-06c9-4f14-bf8b-fafce92d6396");
+    const USER2_ID: Uuid = uuid!("22222222-06c9-4f14-bf8b-fafce92d6396");
+    const USER3_ID: Uuid = uuid!("33333333-06c9-4f14-bf8b-fafce92d6396");
+    const USER4_ID: Uuid = uuid!("44444444-06c9-4f14-bf8b-fafce92d6396");
+    const ADMIN1_ID: Uuid = uuid!("aaaaaaaa-06c9-4f14-bf8b-fafce92d6396");
+    const ADMIN2_ID: Uuid = uuid!("bbbbbbbb-06c9-4f14-bf8b-fafce92d6396");
+
+    #[test]
+    fn it_assigns_opposite_roles_to_partners() {
+        assert_eq!(
+            MatchRole::for_pair(&USER1_ID, &USER2_ID),
+            MatchRole::Offerer
+        );
+        assert_eq!(
+            MatchRole::for_pair(&USER2_ID, &USER1_ID),
+            MatchRole::Answerer
+        );
+    }
+
+    #[test]
+    fn it_masks_blocked_words() {
+        let blocked_words = vec!["darn".to_string(), "heck".to_string()];
+
+        assert_eq!(
+            mask_words("Darn, what the heck! darned", &blocked_words).as_deref(),
+            Some("****, what the ****! darned")
+        );
+        assert_eq!(mask_words("hello there", &blocked_words), None);
+        assert_eq!(mask_words("darn", &[]), None);
+    }
+
+    fn candidate(id: Uuid) -> Candidate {
+        Candidate {
+            id,
+            joined_at: Instant::now(),
+            available_since: Instant::now(),
+            match_count: 0,
+            side: None,
+            profile: Profile::default(),
+        }
+    }
+
+    #[test]
+    fn it_expires_rooms_that_are_idle_or_past_their_expiry() {
+        let config = Config::default();
+        let idle_ttl = Duration::from_secs(60);
+        let new_room = |expires_at| {
+            Room::new(
+                "test".to_string(),
+                expires_at,
+                MatchStrategyKind::default(),
+                &config,
+            )
+        };
+
+        let mut room = new_room(None);
+        assert!(!room.is_expired(idle_ttl));
+        room.empty_since = Some(Instant::now() - Duration::from_secs(61));
+        assert!(room.is_expired(idle_ttl));
+        // someone is connected
+        room.empty_since = None;
+        assert!(!room.is_expired(Duration::ZERO));
+
+        assert!(new_room(Some(unix_timestamp() - 1)).is_expired(idle_ttl));
+        assert!(!new_room(Some(unix_timestamp() + 60)).is_expired(idle_ttl));
+    }
+
+    #[test]
+    fn it_makes_matches_correctly() {
+        let mut users = HashMap::<Uuid, Candidate>::new();
+        let active_matches = Vec::<Vec<Uuid>>::new();
+        let previous_matches = Vec::<Vec<Uuid>>::new();
+
+        users.insert(USER1_ID, candidate(USER1_ID));
+        users.insert(USER2_ID, candidate(USER2_ID));
+
+        // if there are only 2 users with no active or previous, it matches those together
+        let next_match = calculate_next_match(
+            &USER1_ID,
+            &users,
+            &active_matches,
+            &previous_matches,
+            2..=2,
+            false,
+            &Random,
+        );
+
+        assert_eq!(next_match, Some(vec![USER1_ID, USER2_ID]));
+    }
+
+    #[test]
+    fn it_excludes_active_matches() {
+        let mut users = HashMap::<Uuid, Candidate>::new();
+        let mut active_matches = Vec::<Vec<Uuid>>::new();
+        let previous_matches = Vec::<Vec<Uuid>>::new();
+
+        users.insert(USER1_ID, candidate(USER1_ID));
+        users.insert(USER2_ID, candidate(USER2_ID));
+        users.insert(USER3_ID, candidate(USER3_ID));
+
+        active_matches.push(vec![USER1_ID, USER2_ID]);
+
+        // if there are 3 users and 2 are in an active match, it cannot match the third user
+        let next_match = calculate_next_match(
+            &USER3_ID,
+            &users,
+            &active_matches,
+            &previous_matches,
+            2..=2,
+            false,
+            &Random,
+        );
+
+        assert_eq!(next_match, None);
+
+        // if the user is in an active match, it cannot be matched again
+        let next_match = calculate_next_match(
+            &USER1_ID,
+            &users,
+            &active_matches,
+            &previous_matches,
+            2..=2,
+            false,
+            &Random,
+        );
+
+        assert_eq!(next_match, None);
+
+        // if there are 4 users and 2 are in an active match, only one option remains
+        users.insert(USER4_ID, candidate(USER4_ID));
+        let next_match = calculate_next_match(
+            &USER3_ID,
+            &users,
+            &active_matches,
+            &previous_matches,
+            2..=2,
+            false,
+            &Random,
+        );
+
+        assert_eq!(next_match, Some(vec![USER3_ID, USER4_ID]));
+    }
+
+    #[test]
+    fn it_does_not_repeat_matches() {
+        let mut users = HashMap::<Uuid, Candidate>::new();
+        let active_matches = Vec::<Vec<Uuid>>::new();
+        let mut previous_matches = Vec::<Vec<Uuid>>::new();
 
-                    let next_next_match =
-                        calculate_next_match(&USER3_ID, &users, &active_matches, &previous_matches);
+        users.insert(USER1_ID, candidate(USER1_ID));
+        users.insert(USER2_ID, candidate(USER2_ID));
+        users.insert(USER3_ID, candidate(USER3_ID));
 
-                    assert_eq!(next_next_match, Some((USER3_ID, USER2_ID)));
-                    previous_matches.push((USER3_ID, USER2_ID));
+        // Sorting isn't stable so it either matches user 3 with user 2 first and then user 1 or with user 1 first and then user 2. After those 2 matches it must return None as there are no options left.
+        // Running this 100 times to make sure both cases are covered
+        let mut i = 1;
+        while i <= 100 {
+            previous_matches.clear();
 
-                    let next_match =
-                        calculate_next_match(&USER3_ID, &users, &active_matches, &previous_matches);
+            match calculate_next_match(
+                &USER3_ID,
+                &users,
+                &active_matches,
+                &previous_matches,
+                2..=2,
+                false,
+                &Random,
+            )
+            .as_deref()
+            {
+                Some([USER3_ID, USER2_ID]) => {
+                    previous_matches.push(vec![USER3_ID, USER2_ID]);
+
+                    let next_match = calculate_next_match(
+                        &USER3_ID,
+                        &users,
+                        &active_matches,
+                        &previous_matches,
+                        2..=2,
+                        false,
+                        &Random,
+                    );
+
+                    assert_eq!(next_match, Some(vec![USER3_ID, USER1_ID]));
+                    previous_matches.push(vec![USER3_ID, USER1_ID]);
+
+                    let next_match = calculate_next_match(
+                        &USER3_ID,
+                        &users,
+                        &active_matches,
+                        &previous_matches,
+                        2..=2,
+                        false,
+                        &Random,
+                    );
 
                     assert_eq!(next_match, None);
                 }
-                Some((_, _)) => {
+                Some([USER3_ID, USER1_ID]) => {
+                    previous_matches.push(vec![USER3_ID, USER1_ID]);
+
+                    let next_next_match = calculate_next_match(
+                        &USER3_ID,
+                        &users,
+                        &active_matches,
+                        &previous_matches,
+                        2..=2,
+                        false,
+                        &Random,
+                    );
+
+                    assert_eq!(next_next_match, Some(vec![USER3_ID, USER2_ID]));
+                    previous_matches.push(vec![USER3_ID, USER2_ID]);
+
+                    let next_match = calculate_next_match(
+                        &USER3_ID,
+                        &users,
+                        &active_matches,
+                        &previous_matches,
+                        2..=2,
+                        false,
+                        &Random,
+                    );
+
+                    assert_eq!(next_match, None);
+                }
+                Some(_) => {
                     panic!();
                 }
                 None => {
@@ -535,4 +1926,899 @@ mod tests {
             i += 1;
         }
     }
+
+    /// Stands in for a user's `Connection`, recording every event the room sends it.
+    struct TestClient {
+        events: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl Actor for TestClient {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WebSocketMessage> for TestClient {
+        type Result = ();
+
+        fn handle(&mut self, msg: WebSocketMessage, _: &mut Context<Self>) {
+            let event = match msg {
+                WebSocketMessage::Text(text) => serde_json::from_str(&text).unwrap(),
+                // recorded like an event so tests can look for it the same way
+                WebSocketMessage::Close(reason) => json!({
+                    "type": "close",
+                    "data": reason.map(|reason| json!({
+                        "code": u16::from(reason.code),
+                        "description": reason.description,
+                    })),
+                }),
+                WebSocketMessage::Binary(_) => return,
+            };
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    fn start_room(match_duration: u64) -> Addr<Room> {
+        let config = Config {
+            reconnect_grace_period: Duration::from_secs(1),
+            ..Config::default()
+        };
+        let mut room = Room::new(
+            "test".to_string(),
+            None,
+            MatchStrategyKind::default(),
+            &config,
+        );
+        room.settings.match_duration = match_duration;
+        room.start()
+    }
+
+    async fn connect_user(room: &Addr<Room>, id: Uuid) -> Arc<Mutex<Vec<Value>>> {
+        let (events, _) = join(room, id, ClientProtocol::default(), None, None).await;
+        events
+    }
+
+    async fn join(
+        room: &Addr<Room>,
+        connection_id: Uuid,
+        protocol: ClientProtocol,
+        resume_token: Option<String>,
+        side: Option<Side>,
+    ) -> (Arc<Mutex<Vec<Value>>>, Uuid) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let client = TestClient {
+            events: events.clone(),
+        }
+        .start();
+        let id = room
+            .send(ClientConnect::user(
+                client.recipient(),
+                connection_id,
+                protocol,
+                "test".to_string(),
+                resume_token,
+                side,
+                Profile::default(),
+            ))
+            .await
+            .unwrap();
+        (events, id)
+    }
+
+    async fn connect_admin(room: &Addr<Room>, id: Uuid) -> Arc<Mutex<Vec<Value>>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let client = TestClient {
+            events: events.clone(),
+        }
+        .start();
+        room.send(ClientConnect::admin(
+            client.recipient(),
+            id,
+            ClientProtocol::default(),
+        ))
+        .await
+        .unwrap();
+        events
+    }
+
+    async fn disconnect(
+        room: &Addr<Room>,
+        id: Uuid,
+        connection_id: Uuid,
+        closed_deliberately: bool,
+    ) {
+        room.send(ClientDisconnect {
+            id,
+            connection_id,
+            closed_deliberately,
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn send_event(room: &Addr<Room>, id: Uuid, event: Value) {
+        room.send(ClientMessage {
+            id,
+            payload: event.to_string().into_bytes(),
+        })
+        .await
+        .unwrap();
+    }
+
+    fn received(events: &Arc<Mutex<Vec<Value>>>, event_type: &str) -> Vec<Value> {
+        events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event["type"] == event_type)
+            .cloned()
+            .collect()
+    }
+
+    fn ready_to_match() -> Value {
+        json!({ "type": "ready-to-match" })
+    }
+
+    #[actix::test]
+    async fn it_only_uses_the_capabilities_a_client_asked_for() {
+        let room = start_room(10);
+        let admin = connect_admin(&room, ADMIN1_ID).await;
+        let protocol = ClientProtocol {
+            capabilities: Vec::new(),
+            ..ClientProtocol::default()
+        };
+        let (user1, _) = join(&room, USER1_ID, protocol, None, None).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+
+        assert_eq!(
+            received(&user1, "self-joined")[0]["data"].get("resume_token"),
+            None
+        );
+        send_event(&room, USER1_ID, json!({ "type": "unknown" })).await;
+        assert!(received(&user1, "error").is_empty());
+
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        assert_eq!(
+            received(&user1, "user-matched")[0]["data"].get("role"),
+            None
+        );
+        assert!(received(&user2, "user-matched")[0]["data"]
+            .get("role")
+            .is_some());
+        // the partner isn't left thinking a chat message arrived
+        send_event(
+            &room,
+            USER2_ID,
+            json!({ "type": "chat-message", "data": { "text": "hi" } }),
+        )
+        .await;
+        assert!(received(&user1, "chat-message").is_empty());
+        assert_eq!(
+            received(&user2, "error")[0]["data"]["code"],
+            json!("chat_unsupported")
+        );
+
+        // without `resume` there's no session to wait for
+        disconnect(&room, USER1_ID, USER1_ID, false).await;
+        assert_eq!(
+            received(&admin, "user-left")[0]["data"]["id"],
+            json!(USER1_ID)
+        );
+    }
+
+    #[actix::test]
+    async fn it_matches_the_user_who_is_ready() {
+        let room = start_room(2);
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+
+        // ids in the payload are not accepted, the sender can only put themselves into a match
+        send_event(
+            &room,
+            USER1_ID,
+            json!({ "type": "ready-to-match", "data": { "id": USER2_ID } }),
+        )
+        .await;
+        assert!(received(&user1, "user-matched").is_empty());
+        assert!(received(&user2, "user-matched").is_empty());
+
+        send_event(&room, USER2_ID, ready_to_match()).await;
+        assert_eq!(
+            received(&user1, "user-matched")[0]["data"]["id"],
+            json!(USER2_ID)
+        );
+        assert_eq!(
+            received(&user2, "user-matched")[0]["data"]["id"],
+            json!(USER1_ID)
+        );
+    }
+
+    #[actix::test]
+    async fn it_does_not_end_a_newer_match_when_a_partner_left() {
+        time::pause();
+        let room = start_room(2);
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        assert_eq!(received(&user2, "user-matched").len(), 1);
+
+        // user 2 leaves, so user 1 is free to be matched again while the first match's timer
+        // would still be pending
+        disconnect(&room, USER2_ID, USER2_ID, true).await;
+        assert_eq!(received(&user1, "user-left").len(), 1);
+
+        sleep(Duration::from_secs(1)).await;
+        let user3 = connect_user(&room, USER3_ID).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        assert_eq!(received(&user3, "user-matched").len(), 1);
+
+        // the first match's timer would have fired by now but the new match must still be going
+        sleep(Duration::from_millis(1500)).await;
+        assert!(received(&user1, "match-ended").is_empty());
+        assert!(received(&user3, "match-ended").is_empty());
+
+        // until its own time is up
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(
+            received(&user1, "match-ended"),
+            vec![json!({ "type": "match-ended", "data": { "id": USER3_ID } })]
+        );
+        assert_eq!(received(&user3, "match-ended").len(), 1);
+    }
+
+    #[actix::test]
+    async fn it_ends_a_skipped_match_right_away() {
+        time::pause();
+        let room = start_room(2);
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+
+        send_event(&room, USER2_ID, json!({ "type": "skip-match" })).await;
+        assert_eq!(received(&user1, "match-ended").len(), 1);
+        assert_eq!(received(&user2, "match-ended").len(), 1);
+        // leave user 1 as the only possible partner for user 3
+        disconnect(&room, USER2_ID, USER2_ID, true).await;
+
+        // a new match for user 1 must not be ended by the skipped match's timer
+        sleep(Duration::from_secs(1)).await;
+        let user3 = connect_user(&room, USER3_ID).await;
+        send_event(&room, USER3_ID, ready_to_match()).await;
+        assert_eq!(received(&user1, "user-matched").len(), 2);
+
+        sleep(Duration::from_millis(1500)).await;
+        assert_eq!(received(&user1, "match-ended").len(), 1);
+        assert!(received(&user3, "match-ended").is_empty());
+    }
+
+    #[actix::test]
+    async fn it_relays_signaling_only_between_partners() {
+        let room = start_room(2);
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        let user3 = connect_user(&room, USER3_ID).await;
+
+        let offer = |id: Uuid| json!({ "type": "rtc-connection-offer", "data": { "id": id, "description": "sdp" } });
+        send_event(&room, USER1_ID, offer(USER2_ID)).await;
+        assert_eq!(
+            received(&user2, "rtc-connection-offer"),
+            vec![offer(USER1_ID)]
+        );
+
+        // user 3 isn't matched with user 1 so they can't send them anything
+        send_event(&room, USER3_ID, offer(USER1_ID)).await;
+        assert!(received(&user1, "rtc-connection-offer").is_empty());
+        assert_eq!(
+            received(&user3, "error")[0]["data"]["code"],
+            json!("not_matched")
+        );
+    }
+
+    #[actix::test]
+    async fn it_reports_rejected_messages_to_the_sender() {
+        let room = start_room(2);
+        let user1 = connect_user(&room, USER1_ID).await;
+
+        // valid JSON but not an event
+        send_event(&room, USER1_ID, json!("{ not json")).await;
+        room.send(ClientMessage {
+            id: USER1_ID,
+            payload: b"{ not json".to_vec(),
+        })
+        .await
+        .unwrap();
+        send_event(
+            &room,
+            USER1_ID,
+            json!({ "type": "user-left", "data": { "id": USER2_ID }, "correlation_id": "42" }),
+        )
+        .await;
+        send_event(
+            &room,
+            USER1_ID,
+            json!({ "type": "ice-candidate", "data": { "id": USER2_ID, "description": "" } }),
+        )
+        .await;
+
+        let errors: Vec<Value> = received(&user1, "error")
+            .into_iter()
+            .map(|error| error["data"].clone())
+            .collect();
+        assert_eq!(errors[0]["code"], json!("invalid_event"));
+        assert_eq!(errors[1]["code"], json!("invalid_json"));
+        assert_eq!(errors[2]["code"], json!("unexpected_event"));
+        assert_eq!(errors[2]["correlation_id"], json!("42"));
+        assert_eq!(errors[3]["code"], json!("unknown_recipient"));
+        assert_eq!(errors[3].get("correlation_id"), None);
+    }
+
+    #[actix::test]
+    async fn it_ignores_skips_from_users_not_in_a_match() {
+        let room = start_room(2);
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        let user3 = connect_user(&room, USER3_ID).await;
+
+        send_event(&room, USER3_ID, json!({ "type": "skip-match" })).await;
+        assert!(received(&user1, "match-ended").is_empty());
+        assert!(received(&user2, "match-ended").is_empty());
+        assert!(received(&user3, "match-ended").is_empty());
+        assert_eq!(
+            received(&user3, "error")[0]["data"]["code"],
+            json!("not_matched")
+        );
+    }
+
+    #[actix::test]
+    async fn it_resumes_a_session_within_the_grace_period() {
+        time::pause();
+        let room = start_room(10);
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        let resume_token = received(&user2, "self-joined")[0]["data"]["resume_token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        // the connection drops without a close frame
+        disconnect(&room, USER2_ID, USER2_ID, false).await;
+        assert_eq!(
+            received(&user1, "user-reconnecting"),
+            vec![json!({ "type": "user-reconnecting", "data": { "id": USER2_ID } })]
+        );
+
+        let (resumed, id) = join(
+            &room,
+            USER3_ID,
+            ClientProtocol::default(),
+            Some(resume_token),
+            None,
+        )
+        .await;
+        assert_eq!(id, USER2_ID);
+        assert_eq!(
+            received(&resumed, "self-joined")[0]["data"]["id"],
+            json!(USER2_ID)
+        );
+        assert_eq!(
+            received(&resumed, "user-matched")[0]["data"]["id"],
+            json!(USER1_ID)
+        );
+        assert_eq!(received(&user1, "user-reconnected").len(), 1);
+
+        // the grace period timer was cancelled, so the partner is never told the user left
+        sleep(Duration::from_millis(1500)).await;
+        assert!(received(&user1, "user-left").is_empty());
+
+        // and a late disconnect of the replaced connection changes nothing
+        disconnect(&room, USER2_ID, USER2_ID, false).await;
+        assert_eq!(received(&user1, "user-reconnecting").len(), 1);
+    }
+
+    #[actix::test]
+    async fn it_resumes_matches_of_any_duration() {
+        let room = start_room(u64::MAX / 2);
+        connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        let resume_token = received(&user2, "self-joined")[0]["data"]["resume_token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        disconnect(&room, USER2_ID, USER2_ID, false).await;
+        let (resumed, _) = join(
+            &room,
+            USER3_ID,
+            ClientProtocol::default(),
+            Some(resume_token),
+            None,
+        )
+        .await;
+        let remaining = received(&resumed, "user-matched")[0]["data"]["duration"]
+            .as_u64()
+            .unwrap();
+        assert!(remaining > u64::MAX / 4);
+    }
+
+    #[actix::test]
+    async fn it_removes_users_who_do_not_reconnect_in_time() {
+        time::pause();
+        let room = start_room(10);
+        let user1 = connect_user(&room, USER1_ID).await;
+        connect_user(&room, USER2_ID).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+
+        disconnect(&room, USER2_ID, USER2_ID, false).await;
+        assert!(received(&user1, "user-left").is_empty());
+
+        sleep(Duration::from_millis(1500)).await;
+        assert_eq!(received(&user1, "user-left").len(), 1);
+
+        // the session is gone, so its token doesn't resume anything
+        let (_, id) = join(
+            &room,
+            USER3_ID,
+            ClientProtocol::default(),
+            Some("unknown".to_string()),
+            None,
+        )
+        .await;
+        assert_eq!(id, USER3_ID);
+    }
+
+    #[actix::test]
+    async fn it_lets_admins_pair_users_and_end_matches() {
+        let room = start_room(10);
+        let admin = connect_admin(&room, ADMIN1_ID).await;
+        let other_admin = connect_admin(&room, ADMIN2_ID).await;
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+        connect_user(&room, USER3_ID).await;
+
+        let pair_users = json!({
+            "type": "pair-users",
+            "data": { "users": [USER1_ID, USER3_ID] },
+            "correlation_id": "1"
+        });
+        send_event(&room, ADMIN1_ID, pair_users).await;
+        assert_eq!(
+            received(&user1, "user-matched")[0]["data"]["id"],
+            json!(USER3_ID)
+        );
+        assert_eq!(
+            received(&admin, "command-acknowledged")[0]["data"]["correlation_id"],
+            json!("1")
+        );
+        assert_eq!(
+            received(&other_admin, "admin-command-executed")[0]["data"]["id"],
+            json!(ADMIN1_ID)
+        );
+
+        // user 1 is taken now
+        send_event(
+            &room,
+            ADMIN1_ID,
+            json!({ "type": "pair-users", "data": { "users": [USER2_ID, USER1_ID] } }),
+        )
+        .await;
+        assert_eq!(
+            received(&admin, "error")[0]["data"]["code"],
+            json!("already_matched")
+        );
+        assert!(received(&user2, "user-matched").is_empty());
+
+        send_event(
+            &room,
+            ADMIN1_ID,
+            json!({ "type": "end-match", "data": { "id": USER3_ID } }),
+        )
+        .await;
+        assert_eq!(received(&user1, "match-ended").len(), 1);
+    }
+
+    #[actix::test]
+    async fn it_holds_off_matching_while_paused() {
+        let room = start_room(10);
+        connect_admin(&room, ADMIN1_ID).await;
+        let user1 = connect_user(&room, USER1_ID).await;
+        connect_user(&room, USER2_ID).await;
+
+        send_event(&room, ADMIN1_ID, json!({ "type": "pause-matching" })).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        assert!(received(&user1, "user-matched").is_empty());
+        assert_eq!(received(&user1, "queued").len(), 1);
+
+        send_event(&room, ADMIN1_ID, json!({ "type": "resume-matching" })).await;
+        assert_eq!(received(&user1, "user-matched").len(), 1);
+    }
+
+    #[actix::test]
+    async fn it_lets_admins_kick_users() {
+        let room = start_room(10);
+        let admin = connect_admin(&room, ADMIN1_ID).await;
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+
+        send_event(
+            &room,
+            ADMIN1_ID,
+            json!({ "type": "kick-user", "data": { "id": USER2_ID, "reason": "spam" } }),
+        )
+        .await;
+        assert_eq!(
+            received(&user2, "close")[0]["data"],
+            json!({ "code": 1008, "description": "kicked by an admin: spam" })
+        );
+        assert_eq!(received(&user1, "user-left").len(), 1);
+        assert_eq!(received(&admin, "user-left").len(), 1);
+
+        let user3 = connect_user(&room, USER3_ID).await;
+        send_event(
+            &room,
+            ADMIN1_ID,
+            json!({ "type": "kick-user", "data": { "id": USER3_ID, "reason": "ä".repeat(200) } }),
+        )
+        .await;
+        let description = received(&user3, "close")[0]["data"]["description"].clone();
+        assert_eq!(
+            description,
+            json!(format!("kicked by an admin: {}", "ä".repeat(51)))
+        );
+
+        // users can't send admin commands
+        send_event(
+            &room,
+            USER1_ID,
+            json!({ "type": "kick-user", "data": { "id": USER1_ID, "reason": null } }),
+        )
+        .await;
+        assert_eq!(
+            received(&user1, "error")[0]["data"]["code"],
+            json!("invalid_event")
+        );
+    }
+
+    #[actix::test]
+    async fn it_relays_chat_messages_to_the_partner() {
+        let room = start_room(10);
+        room.send(UpdateRoom {
+            name: None,
+            settings: Some(RoomSettingsUpdate {
+                blocked_words: Some(vec!["heck".to_string()]),
+                ..RoomSettingsUpdate::default()
+            }),
+        })
+        .await
+        .unwrap();
+        let admin = connect_admin(&room, ADMIN1_ID).await;
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+        let user3 = connect_user(&room, USER3_ID).await;
+        let chat_message = |text: &str| json!({ "type": "chat-message", "data": { "text": text } });
+
+        send_event(&room, USER1_ID, chat_message("anyone there?")).await;
+        assert_eq!(
+            received(&user1, "error")[0]["data"]["code"],
+            json!("not_matched")
+        );
+
+        send_event(
+            &room,
+            ADMIN1_ID,
+            json!({ "type": "pair-users", "data": { "users": [USER1_ID, USER2_ID] } }),
+        )
+        .await;
+        send_event(
+            &room,
+            ADMIN1_ID,
+            json!({ "type": "moderate-chat", "data": { "enabled": true } }),
+        )
+        .await;
+        send_event(&room, USER1_ID, chat_message("what the heck")).await;
+
+        let messages = received(&user2, "chat-message");
+        assert_eq!(messages[0]["data"]["id"], json!(USER1_ID));
+        assert_eq!(messages[0]["data"]["text"], json!("what the ****"));
+        assert!(messages[0]["data"]["sent_at"].as_u64().unwrap() > 0);
+        assert!(received(&user3, "chat-message").is_empty());
+        assert_eq!(
+            received(&admin, "chat-message-copy")[0]["data"]["text"],
+            json!("what the heck")
+        );
+
+        send_event(&room, USER2_ID, chat_message(&"a".repeat(1001))).await;
+        assert_eq!(
+            received(&user2, "error")[0]["data"]["code"],
+            json!("message_too_long")
+        );
+        assert!(received(&user1, "chat-message").is_empty());
+    }
+
+    #[actix::test]
+    async fn it_shows_announcements_until_they_expire() {
+        let room = start_room(10);
+        connect_admin(&room, ADMIN1_ID).await;
+        let user1 = connect_user(&room, USER1_ID).await;
+
+        send_event(
+            &room,
+            ADMIN1_ID,
+            json!({ "type": "announce", "data": { "message": "welcome" } }),
+        )
+        .await;
+        send_event(
+            &room,
+            ADMIN1_ID,
+            json!({
+                "type": "announce",
+                "data": {
+                    "message": "last round in 5 minutes",
+                    "severity": "warning",
+                    "expires_at": unix_timestamp() + 300
+                }
+            }),
+        )
+        .await;
+        let announcements = received(&user1, "announcement");
+        assert_eq!(announcements.len(), 2);
+        assert_eq!(announcements[0]["data"]["severity"], json!("info"));
+        assert_eq!(announcements[1]["data"]["severity"], json!("warning"));
+
+        // only announcements that expire later are shown to users who join afterwards
+        let user2 = connect_user(&room, USER2_ID).await;
+        let announcements = received(&user2, "announcement");
+        assert_eq!(announcements.len(), 1);
+        assert_eq!(
+            announcements[0]["data"]["message"],
+            json!("last round in 5 minutes")
+        );
+    }
+
+    #[actix::test]
+    async fn it_pairs_everyone_in_rounds() {
+        // paused time jumps to the next timer as soon as nothing else is left to do
+        time::pause();
+        let room = start_room(1);
+        let admin = connect_admin(&room, ADMIN1_ID).await;
+        let users = [
+            connect_user(&room, USER1_ID).await,
+            connect_user(&room, USER2_ID).await,
+            connect_user(&room, USER3_ID).await,
+        ];
+
+        send_event(&room, ADMIN1_ID, json!({ "type": "start-round" })).await;
+        let round_started = &received(&admin, "round-started")[0]["data"];
+        assert_eq!(round_started["round"], json!(1));
+        assert_eq!(round_started["byes"].as_array().unwrap().len(), 1);
+        let byes = users
+            .iter()
+            .filter(|events| !received(events, "round-bye").is_empty())
+            .count();
+        assert_eq!(byes, 1);
+
+        // with 3 users, everyone met everyone after 3 rounds
+        sleep(Duration::from_millis(3200)).await;
+        assert_eq!(received(&admin, "round-started").len(), 3);
+        assert_eq!(
+            received(&admin, "rounds-finished")[0]["data"]["rounds"],
+            json!(3)
+        );
+        for events in &users {
+            assert_eq!(received(events, "user-matched").len(), 2);
+            assert_eq!(received(events, "round-bye").len(), 1);
+        }
+    }
+
+    #[actix::test]
+    async fn it_does_not_match_queued_users_while_a_round_starts() {
+        let room = start_room(10);
+        let admin = connect_admin(&room, ADMIN1_ID).await;
+        connect_user(&room, USER1_ID).await;
+        connect_user(&room, USER2_ID).await;
+        let user3 = connect_user(&room, USER3_ID).await;
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        send_event(&room, USER3_ID, ready_to_match()).await;
+        assert_eq!(received(&user3, "queued").len(), 1);
+
+        send_event(&room, ADMIN1_ID, json!({ "type": "start-round" })).await;
+        // user 3 is only matched once, by the round
+        assert_eq!(received(&user3, "user-matched").len(), 1);
+        let matches = received(&admin, "active-matches-changed");
+        assert_eq!(
+            matches.last().unwrap()["data"]["matches"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[actix::test]
+    async fn it_matches_pods_of_users() {
+        let room = start_room(10);
+        room.send(UpdateRoom {
+            name: None,
+            settings: Some(RoomSettingsUpdate {
+                pod_size: Some(3),
+                ..RoomSettingsUpdate::default()
+            }),
+        })
+        .await
+        .unwrap();
+        let admin = connect_admin(&room, ADMIN1_ID).await;
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+
+        // two users aren't enough for a pod
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        assert!(received(&user1, "user-matched").is_empty());
+
+        let user3 = connect_user(&room, USER3_ID).await;
+        for events in [&user1, &user2, &user3] {
+            assert_eq!(received(events, "user-matched").len(), 2);
+        }
+        let matches = received(&admin, "active-matches-changed");
+        assert_eq!(
+            matches.last().unwrap()["data"]["matches"][0]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+
+        // signaling is relayed between all members of the pod
+        send_event(
+            &room,
+            USER2_ID,
+            json!({ "type": "rtc-connection-offer", "data": { "id": USER3_ID, "description": "offer" } }),
+        )
+        .await;
+        assert_eq!(received(&user3, "rtc-connection-offer").len(), 1);
+
+        // the pod goes on without a user who skips it
+        send_event(&room, USER1_ID, json!({ "type": "skip-match" })).await;
+        assert_eq!(received(&user1, "match-ended").len(), 2);
+        assert_eq!(
+            received(&user2, "match-ended")[0]["data"]["id"],
+            json!(USER1_ID)
+        );
+        send_event(
+            &room,
+            USER3_ID,
+            json!({ "type": "rtc-connection-answer", "data": { "id": USER2_ID, "description": "answer" } }),
+        )
+        .await;
+        assert_eq!(received(&user2, "rtc-connection-answer").len(), 1);
+        assert!(received(&user3, "error").is_empty());
+    }
+
+    #[actix::test]
+    async fn it_matches_smaller_pods_when_users_waited_too_long() {
+        time::pause();
+        let room = start_room(60);
+        room.send(UpdateRoom {
+            name: None,
+            settings: Some(RoomSettingsUpdate {
+                pod_size: Some(3),
+                ..RoomSettingsUpdate::default()
+            }),
+        })
+        .await
+        .unwrap();
+        let user1 = connect_user(&room, USER1_ID).await;
+        let user2 = connect_user(&room, USER2_ID).await;
+
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        sleep(POD_FILL_TIMEOUT - Duration::from_secs(1)).await;
+        assert!(received(&user1, "user-matched").is_empty());
+
+        // rather than waiting for a third user forever, the two of them form a pod
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            received(&user1, "user-matched")[0]["data"]["id"],
+            json!(USER2_ID)
+        );
+        assert_eq!(received(&user2, "user-matched").len(), 1);
+    }
+
+    #[actix::test]
+    async fn it_only_matches_users_across_sides() {
+        let room = start_room(10);
+        room.send(UpdateRoom {
+            name: None,
+            settings: Some(RoomSettingsUpdate {
+                bipartite: Some(true),
+                ..RoomSettingsUpdate::default()
+            }),
+        })
+        .await
+        .unwrap();
+        let admin = connect_admin(&room, ADMIN1_ID).await;
+        let (user1, _) = join(
+            &room,
+            USER1_ID,
+            ClientProtocol::default(),
+            None,
+            Some(Side::A),
+        )
+        .await;
+        join(
+            &room,
+            USER2_ID,
+            ClientProtocol::default(),
+            None,
+            Some(Side::A),
+        )
+        .await;
+        let user3 = connect_user(&room, USER3_ID).await;
+
+        send_event(&room, USER1_ID, ready_to_match()).await;
+        assert!(received(&user1, "user-matched").is_empty());
+        send_event(&room, USER3_ID, ready_to_match()).await;
+        assert_eq!(
+            received(&user3, "error")[0]["data"]["code"],
+            json!("side_required")
+        );
+
+        let (user4, _) = join(
+            &room,
+            USER4_ID,
+            ClientProtocol::default(),
+            None,
+            Some(Side::B),
+        )
+        .await;
+        assert_eq!(
+            received(&user4, "user-matched")[0]["data"]["id"],
+            json!(USER1_ID)
+        );
+        assert_eq!(
+            received(&admin, "user-joined")[3]["data"]["side"],
+            json!("b")
+        );
+        assert_eq!(
+            received(&admin, "group-balance-changed").last().unwrap()["data"]["balance"],
+            json!({ "a": 2, "b": 1, "unassigned": 1 })
+        );
+    }
+
+    #[actix::test]
+    async fn it_sends_the_group_balance_when_rooms_start_matching_across_sides() {
+        let room = start_room(10);
+        let admin = connect_admin(&room, ADMIN1_ID).await;
+        join(
+            &room,
+            USER1_ID,
+            ClientProtocol::default(),
+            None,
+            Some(Side::A),
+        )
+        .await;
+        connect_user(&room, USER2_ID).await;
+        assert!(received(&admin, "group-balance-changed").is_empty());
+
+        let update = || UpdateRoom {
+            name: None,
+            settings: Some(RoomSettingsUpdate {
+                bipartite: Some(true),
+                ..RoomSettingsUpdate::default()
+            }),
+        };
+        room.send(update()).await.unwrap();
+        let balances = received(&admin, "group-balance-changed");
+        assert_eq!(
+            balances,
+            vec![json!({
+                "type": "group-balance-changed",
+                "data": { "balance": { "a": 1, "b": 0, "unassigned": 1 } }
+            })]
+        );
+
+        // nothing changed the second time
+        room.send(update()).await.unwrap();
+        assert_eq!(received(&admin, "group-balance-changed").len(), 1);
+    }
 }
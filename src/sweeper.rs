@@ -0,0 +1,90 @@
+use actix::Addr;
+use actix_web::{rt::time, web::Data};
+use uuid::Uuid;
+
+use crate::messages::{CloseRoom, IsRoomExpired};
+use crate::room::Room;
+use crate::AppState;
+
+/// Periodically removes rooms that have been empty for longer than the configured idle TTL or
+/// that have passed their `expires_at`, stopping their actors.
+pub async fn sweep_rooms(data: Data<AppState>) {
+    let mut interval = time::interval(data.config.room_sweep_interval);
+
+    loop {
+        interval.tick().await;
+
+        let rooms: Vec<(Uuid, Addr<Room>)> = data
+            .rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, addr)| (*id, addr.clone()))
+            .collect();
+
+        for (id, addr) in rooms {
+            let is_expired = addr
+                .send(IsRoomExpired {
+                    idle_ttl: data.config.room_idle_ttl,
+                })
+                .await
+                // a room whose actor is gone can't be used anymore either
+                .unwrap_or(true);
+
+            if is_expired {
+                println!("🧹 Removing expired room {:?}", id);
+                data.rooms.lock().unwrap().remove(&id);
+                addr.do_send(CloseRoom);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sweep_rooms;
+    use crate::config::Config;
+    use crate::matching::MatchStrategyKind;
+    use crate::room::Room;
+    use crate::util::unix_timestamp;
+    use crate::AppState;
+    use actix::{clock::sleep, Actor};
+    use actix_web::web::Data;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[actix::test]
+    async fn it_removes_expired_rooms() {
+        time::pause();
+        let config = Config {
+            room_sweep_interval: Duration::from_millis(10),
+            ..Config::default()
+        };
+        let expired = Room::new(
+            "expired".to_string(),
+            Some(unix_timestamp() - 1),
+            MatchStrategyKind::default(),
+            &config,
+        );
+        let open = Room::new(
+            "open".to_string(),
+            None,
+            MatchStrategyKind::default(),
+            &config,
+        );
+        let (expired_id, open_id) = (expired.id, open.id);
+        let data = Data::new(AppState::new(config));
+        {
+            let mut rooms = data.rooms.lock().unwrap();
+            rooms.insert(expired_id, expired.start());
+            rooms.insert(open_id, open.start());
+        }
+
+        actix::spawn(sweep_rooms(data.clone()));
+        sleep(Duration::from_millis(50)).await;
+
+        let rooms = data.rooms.lock().unwrap();
+        assert!(!rooms.contains_key(&expired_id));
+        assert!(rooms.contains_key(&open_id));
+    }
+}
@@ -1,4 +1,5 @@
 use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn is_dev_mode() -> bool {
     let args: Vec<String> = env::args().collect();
@@ -7,3 +8,70 @@ pub fn is_dev_mode() -> bool {
         None => false,
     }
 }
+
+/// Returns the current time as seconds since the Unix epoch.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the current time as milliseconds since the Unix epoch.
+pub fn unix_timestamp_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Compares two byte strings in time independent of where they differ, so
+/// secrets like admin tokens can't be guessed byte by byte via timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Shortens `text` to at most `max_len` bytes without splitting a character.
+pub fn truncate_to_char_boundary(text: &mut String, max_len: usize) {
+    if text.len() <= max_len {
+        return;
+    }
+    let len = (0..=max_len)
+        .rev()
+        .find(|index| text.is_char_boundary(*index))
+        .unwrap_or(0);
+    text.truncate(len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{constant_time_eq, truncate_to_char_boundary};
+
+    #[test]
+    fn it_compares_equal_strings() {
+        assert!(constant_time_eq(b"secret-token", b"secret-token"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn it_rejects_different_strings() {
+        assert!(!constant_time_eq(b"secret-token", b"secret-tokem"));
+        assert!(!constant_time_eq(b"secret-token", b"secret"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn it_truncates_between_characters() {
+        let mut text = "aää".to_string();
+        truncate_to_char_boundary(&mut text, 4);
+        assert_eq!(text, "aä");
+
+        let mut text = "short".to_string();
+        truncate_to_char_boundary(&mut text, 10);
+        assert_eq!(text, "short");
+    }
+}